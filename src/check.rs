@@ -0,0 +1,172 @@
+use std::io::{Error, ErrorKind, Result};
+
+use tokio::net::UdpSocket;
+
+use crate::{endpoint::Endpoint, loops_back, resolve, Codec, Config};
+
+/// what `--check` validates, printing each finding; fails if anything is wrong
+pub async fn check(config: &Config) -> Result<()> {
+    let mut problems = 0;
+    let mut report = |r: std::result::Result<String, String>| match r {
+        Ok(ok) => println!("ok: {}", ok),
+        Err(err) => {
+            println!("error: {}", err);
+            problems += 1;
+        }
+    };
+
+    // both are required without a subcommand
+    let (listen, dst) = (
+        config.listen.as_deref().unwrap(),
+        config.dst.as_deref().unwrap(),
+    );
+
+    // sockets are dropped right away, nothing stays bound
+    let mut local = None;
+    report(match crate::activated_socket()? {
+        Some(usock) => {
+            local = Some(usock.local_addr()?);
+            Ok(format!(
+                "systemd passed a socket on {}",
+                usock.local_addr()?
+            ))
+        }
+        None => match UdpSocket::bind(listen).await {
+            Ok(usock) => {
+                local = Some(usock.local_addr()?);
+                Ok(format!("can listen on {}", usock.local_addr()?))
+            }
+            Err(err) => Err(format!("cannot listen on {}: {}", listen, err)),
+        },
+    });
+
+    for (what, addr) in [
+        ("health checks", &config.health_addr),
+        ("admin commands", &config.control_addr),
+    ] {
+        if let Some(addr) = addr {
+            // a Unix socket is tried next to the path, which a running instance may serve on
+            let probe = match addr.contains('/') {
+                true => format!("{}.{}.check", addr, std::process::id()),
+                false => addr.clone(),
+            };
+            report(match Endpoint::bind(&probe).await {
+                Ok(_) => Ok(format!("can serve {} on {}", what, addr)),
+                Err(err) => Err(format!("cannot serve {} on {}: {}", what, addr, err)),
+            });
+        }
+    }
+
+    for addr in [Some(dst), config.backup_dst.as_deref()]
+        .into_iter()
+        .flatten()
+    {
+        report(match resolve(addr, config).await {
+            Ok(resolved) if !config.force && local.is_some_and(|l| loops_back(l, resolved)) => {
+                Err(format!(
+                    "{} resolves to {}, the listener on {}, datagrams would loop",
+                    addr,
+                    resolved,
+                    local.unwrap()
+                ))
+            }
+            Ok(resolved) => Ok(format!("{} resolves to {}", addr, resolved)),
+            Err(err) => Err(err.to_string()),
+        });
+    }
+
+    // on a relay socket of the kind sessions get
+    if config.flow_label {
+        if let Ok(dst) = resolve(dst, config).await {
+            report(match UdpSocket::bind(crate::any_addr(dst)).await {
+                Ok(usock) => match crate::flow_label(&usock) {
+                    Ok(()) => Ok(format!("can set flow labels on {}", usock.local_addr()?)),
+                    Err(err) if config.strict_sockopts => Err(err.to_string()),
+                    Err(err) => Ok(format!("relaying without flow labels: {}", err)),
+                },
+                Err(err) => Err(format!("cannot bind a relay socket: {}", err)),
+            });
+        }
+    }
+
+    if config.user.is_some() || config.group.is_some() {
+        report(check_privileges(config));
+    }
+
+    for problem in validate(config) {
+        report(Err(String::from(problem)));
+    }
+
+    if problems > 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} problems found", problems),
+        ));
+    }
+    println!("configuration is valid");
+    Ok(())
+}
+
+/// options that don't go together, refused by `--check` and at startup alike
+pub fn validate(config: &Config) -> Vec<&'static str> {
+    #[cfg(feature = "dns")]
+    let crowded = !config.client && config.codec == Codec::Txt && !crate::dns::fits(config);
+    #[cfg(not(feature = "dns"))]
+    let crowded = false;
+
+    [
+        (
+            config.session_burst.is_some() && config.session_rate.is_none(),
+            "--session-burst needs --session-rate",
+        ),
+        (
+            config.failback_after.is_some() && config.backup_dst.is_none(),
+            "--failback-after needs --backup-dst",
+        ),
+        (
+            config.client && config.fixed_id.is_some(),
+            "--fixed-id only applies to replies encoded by the server",
+        ),
+        (
+            cfg!(not(feature = "dns")) && config.codec == Codec::Txt,
+            "--codec txt needs a build with the dns feature",
+        ),
+        (
+            config.codec == Codec::RawPassthrough && config.fixed_id.is_some(),
+            "--fixed-id has no effect with --codec raw-passthrough",
+        ),
+        (
+            crowded,
+            "--max-size leaves fragments too small to carry the largest datagram",
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(bad, problem)| bad.then_some(problem))
+    .collect()
+}
+
+#[cfg(unix)]
+fn check_privileges(config: &Config) -> std::result::Result<String, String> {
+    let (uid, gid) = crate::lookup_ids(config.user.as_deref(), config.group.as_deref())
+        .map_err(|err| err.to_string())?;
+
+    // the same rule as when starting
+    if crate::needs_root(uid, gid) {
+        return Err(String::from("switching user or group needs root"));
+    }
+
+    // SAFETY: plain syscalls
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let (uid, gid) = (uid.unwrap_or(euid), gid.unwrap_or(egid));
+    if uid != 0 && gid == 0 {
+        return Err(format!("uid {} would keep gid 0, pass --group", uid));
+    }
+    Ok(format!("can switch to uid {}, gid {}", uid, gid))
+}
+
+#[cfg(not(unix))]
+fn check_privileges(_config: &Config) -> std::result::Result<String, String> {
+    Err(String::from(
+        "--user and --group are only supported on unix",
+    ))
+}
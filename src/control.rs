@@ -0,0 +1,90 @@
+use log::{debug, info};
+use std::{io::Result, net::SocketAddr, sync::Arc};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{
+    client_key,
+    endpoint::{Endpoint, Stream},
+    Context, SessionKey,
+};
+
+/// line based admin commands, one per line, each answered by its output and then a line
+/// "ok" or "error: <why>":
+///
+/// - `list`: one line per session, its source, its relay socket once bound and, with
+///   `--session-id`, its id
+/// - `kill <source or id>`: ends the sessions of that client right away
+pub async fn serve(listener: Endpoint, ctx: Arc<Context>) -> Result<()> {
+    loop {
+        let (stream, from) = listener.accept().await?;
+        debug!("control connection from {}", from);
+
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = session(stream, &ctx).await {
+                debug!("control connection failed: {}", err);
+            }
+        });
+    }
+}
+
+async fn session(stream: Box<dyn Stream>, ctx: &Context) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+
+    while stream.read_line(&mut line).await? > 0 {
+        let reply = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => String::new(),
+            ["list"] => list(ctx).await + "ok\n",
+            ["kill", client] => match kill(ctx, client).await {
+                Some(0) => String::from("error: no such session\n"),
+                Some(n) => format!("killed {}\nok\n", n),
+                None => String::from("error: not an address or session id\n"),
+            },
+            _ => String::from("error: unknown command, try list or kill <source>\n"),
+        };
+        stream.write_all(reply.as_bytes()).await?;
+        line.clear();
+    }
+    Ok(())
+}
+
+async fn list(ctx: &Context) -> String {
+    let table = ctx.table.lock().await;
+    table
+        .iter()
+        .map(|(key, session)| {
+            let local = session
+                .local
+                .map_or(String::from("-"), |local| local.to_string());
+            match key {
+                SessionKey::Addr(_) => format!("{} {}\n", *session.peer.borrow(), local),
+                SessionKey::Id(id) => format!("{} {} {}\n", *session.peer.borrow(), local, id),
+            }
+        })
+        .collect()
+}
+
+/// drops the sessions of `client`, an address or a session id, returning how many;
+/// their relays stop once their queue is drained and closed
+async fn kill(ctx: &Context, client: &str) -> Option<usize> {
+    // sessions are matched on address, or on id with `--session-id`
+    let (addr, id) = match client.parse::<SocketAddr>() {
+        Ok(addr) => (Some(client_key(addr)), None),
+        Err(_) => (None, Some(SessionKey::Id(client.parse().ok()?))),
+    };
+
+    let mut table = ctx.table.lock().await;
+    let before = table.len();
+    table.retain(|key, session| {
+        let peer = client_key(*session.peer.borrow());
+        !(Some(*key) == id || addr.is_some_and(|a| *key == SessionKey::Addr(a) || peer == a))
+    });
+    let killed = before - table.len();
+
+    if killed > 0 {
+        info!("killed {} sessions of {}", killed, client);
+    }
+    Some(killed)
+}
@@ -0,0 +1,1331 @@
+use log::{debug, warn};
+use rand::{seq::SliceRandom, Rng};
+use std::{cmp::min, collections::HashMap, sync::atomic::Ordering};
+
+use bytes::Bytes;
+
+use tokio::time::{Duration, Instant};
+
+use trust_dns_proto::{
+    error::ProtoResult,
+    op::{Header, Message, MessageType, OpCode, Query},
+    rr::{rdata::TXT, Name, RData, Record, RecordType},
+    serialize::binary::{BinDecodable, BinDecoder},
+};
+
+use crate::{AnswerOrder, Config, Duplicates, Section, Stats, DOWN, MAX_DATAGRAM};
+
+/// largest UDP payload IPv4 can send, what replies are kept within whatever `--max-size` says
+const MAX_MESSAGE: usize = 65507;
+
+/// fixed DNS message header
+const HEADER_L: usize = 12;
+/// a TXT record around its strings: root name, type, class, ttl and rdlength
+const RECORD_OVERHEAD: usize = 11;
+/// length octet of each character-string
+const STRING_OVERHEAD: usize = 1;
+/// room for the index string leading each record with `--answer-order indexed`
+const INDEX_OVERHEAD: usize = STRING_OVERHEAD + 5;
+/// room for the "<index>.<count>." question tagging a fragment
+const FRAGMENT_OVERHEAD: usize = 17;
+/// length of each fragment, carried in its group's parity
+const PARITY_LEN: usize = 2;
+/// first character of a decoy record, outside the base64 alphabet
+const DECOY_MARKER: u8 = b'-';
+/// most fragments a reply is split into, and taken to be; `fits` leaves room in each for the
+/// largest datagram to take no more
+const MAX_FRAGMENTS: usize = 0x1000;
+
+/// DNS replies carrying `buf`, several fragments if it doesn't fit `--max-size`
+pub fn dns_reply_encode(buf: &[u8], config: &Config) -> Vec<Bytes> {
+    let max_size = usize::from(config.max_size)
+        .min(MAX_MESSAGE)
+        .saturating_sub(decoys_size(config));
+
+    let id = config.fixed_id.unwrap_or_else(rand::random);
+
+    if HEADER_L + encoded_size(buf.len(), config) <= max_size {
+        return dns_message(id, None, buf, config).into_iter().collect();
+    }
+
+    // too big for one message, split the payload itself so every fragment is
+    // complete base64 on its own
+    let data = fragment_data(max_size, config).unwrap_or(0);
+    let count = match data {
+        0 => usize::MAX,
+        data => buf.len().div_ceil(data),
+    };
+    if count > MAX_FRAGMENTS {
+        warn!(
+            "{} bytes don't fit in {} fragments, the most a client takes",
+            buf.len(),
+            MAX_FRAGMENTS
+        );
+        return vec![];
+    }
+    let fragments: Vec<&[u8]> = buf.chunks(data).collect();
+
+    // parity fragments follow the data ones, one per group
+    let parities: Vec<Vec<u8>> = match config.fec_group {
+        Some(group) => fragments
+            .chunks(group.into())
+            .map(|members| parity(members, members[0].len()))
+            .collect(),
+        None => vec![],
+    };
+
+    debug!(
+        "splitting {} bytes into {} fragments and {} parity fragments",
+        buf.len(),
+        count,
+        parities.len()
+    );
+
+    fragments
+        .into_iter()
+        .chain(parities.iter().map(Vec::as_slice))
+        .enumerate()
+        .map(|(i, f)| dns_message(id, Some((i, count)), f, config))
+        .collect::<Option<Vec<_>>>()
+        .unwrap_or_default()
+}
+
+/// xor of a group's fragments, each taken as its length followed by its bytes padded to `width`
+fn parity(members: &[&[u8]], width: usize) -> Vec<u8> {
+    let mut p = vec![0_u8; PARITY_LEN + width];
+    for m in members {
+        let len = (m.len() as u16).to_be_bytes();
+        for (p, b) in p.iter_mut().zip(len.iter().chain(m.iter())) {
+            *p ^= b;
+        }
+    }
+    p
+}
+
+/// `--max-txt-bytes`, or what a message of `--max-size` can hold at most
+fn max_txt_bytes(config: &Config) -> usize {
+    config
+        .max_txt_bytes
+        .unwrap_or(usize::from(config.max_size).min(MAX_MESSAGE) - HEADER_L - RECORD_OVERHEAD)
+}
+
+/// whether `--max-size` leaves enough room next to the decoys for the largest datagram to be
+/// split into `MAX_FRAGMENTS` at most
+pub fn fits(config: &Config) -> bool {
+    usize::from(config.max_size)
+        .min(MAX_MESSAGE)
+        .checked_sub(decoys_size(config))
+        .and_then(|max_size| fragment_data(max_size, config))
+        .is_some_and(|data| data * MAX_FRAGMENTS >= MAX_DATAGRAM)
+}
+
+/// payload bytes in each fragment of a split reply within `max_size`, if any
+fn fragment_data(max_size: usize, config: &Config) -> Option<usize> {
+    let budget = max_size.checked_sub(HEADER_L + FRAGMENT_OVERHEAD)?;
+    let capacity = fragment_capacity(budget, config);
+    match config.fec_group {
+        // parity is a fragment plus its length, and has to fit as well
+        Some(_) => capacity.checked_sub(PARITY_LEN),
+        None => Some(capacity),
+    }
+    .filter(|&data| data > 0)
+}
+
+/// most bytes the decoys of one message can take
+fn decoys_size(config: &Config) -> usize {
+    usize::from(config.decoy_records)
+        * (RECORD_OVERHEAD + STRING_OVERHEAD + usize::from(config.txt_chunk))
+}
+
+/// a TXT record of one random string, as long as a data string can be
+fn decoy(config: &Config) -> Record {
+    let len = rand::thread_rng().gen_range(1..=usize::from(config.txt_chunk));
+    decoy_record(&[len], config)
+}
+
+/// a decoy taking `bytes` on the wire, or the 13 of the shortest one
+fn padding(bytes: usize, config: &Config) -> Record {
+    let chunk = usize::from(config.txt_chunk);
+    let room = bytes.saturating_sub(RECORD_OVERHEAD).max(2);
+    let (full, rest) = (
+        room / (chunk + STRING_OVERHEAD),
+        room % (chunk + STRING_OVERHEAD),
+    );
+
+    // a last string of a single length octet is the empty string
+    let mut lens = vec![chunk; full];
+    if rest > 0 {
+        lens.push(rest - STRING_OVERHEAD);
+    }
+    decoy_record(&lens, config)
+}
+
+/// a TXT record of random strings `lens` long, the first of at least one character
+/// as it starts with `DECOY_MARKER`
+fn decoy_record(lens: &[usize], config: &Config) -> Record {
+    let mut rng = rand::thread_rng();
+    let mut strings: Vec<String> = lens
+        .iter()
+        .map(|&len| {
+            let mut random = vec![0_u8; len];
+            rng.fill(&mut random[..]);
+            let mut s = base64::encode(random);
+            s.truncate(len);
+            s
+        })
+        .collect();
+    strings[0].replace_range(..1, &char::from(DECOY_MARKER).to_string());
+
+    let mut r = Record::new();
+    r.set_record_type(RecordType::TXT)
+        .set_ttl(config.answer_ttl)
+        .set_data(Some(RData::TXT(TXT::new(strings))));
+    r
+}
+
+/// bytes taken by the records carrying `len` bytes of payload
+fn encoded_size(len: usize, config: &Config) -> usize {
+    let chars = len.div_ceil(3) * 4;
+    let strings = chars.div_ceil(config.txt_chunk.into()).max(1);
+    chars
+        + strings * STRING_OVERHEAD
+        + strings.div_ceil(config.txt_strings.into()) * record_overhead(config)
+}
+
+fn record_overhead(config: &Config) -> usize {
+    match config.answer_order {
+        AnswerOrder::Ordered => RECORD_OVERHEAD,
+        AnswerOrder::Indexed => RECORD_OVERHEAD + INDEX_OVERHEAD,
+    }
+}
+
+/// most payload bytes whose records fit in `budget`
+fn fragment_capacity(budget: usize, config: &Config) -> usize {
+    let per_record = usize::from(config.txt_strings);
+    let chunk = usize::from(config.txt_chunk);
+    let string = chunk + STRING_OVERHEAD;
+    let record = record_overhead(config) + per_record * string;
+
+    // whole records, then whole strings of a last record, then a last string
+    let records = budget / record;
+    let rest = (budget - records * record).saturating_sub(record_overhead(config));
+    let strings = rest / string;
+    let last = (rest - strings * string).saturating_sub(STRING_OVERHEAD);
+
+    let chars = (records * per_record + strings) * chunk + last;
+    chars / 4 * 3
+}
+
+fn dns_message(
+    id: u16,
+    fragment: Option<(usize, usize)>,
+    buf: &[u8],
+    config: &Config,
+) -> Option<Bytes> {
+    let s = base64::encode(buf);
+    let chunk = usize::from(config.txt_chunk);
+    let strings: Vec<String> = (0..s.len())
+        .step_by(chunk)
+        .map(|i| String::from(&s[i..min(i + chunk, s.len())]))
+        .collect();
+    // an empty datagram still gets an (empty) answer, telling it apart from a reply without any
+    let strings = if strings.is_empty() {
+        vec![String::new()]
+    } else {
+        strings
+    };
+
+    let records = strings
+        .chunks(config.txt_strings.into())
+        .enumerate()
+        .map(|(i, txt)| {
+            let txt = match config.answer_order {
+                AnswerOrder::Ordered => txt.to_vec(),
+                AnswerOrder::Indexed => [&[i.to_string()], txt].concat(),
+            };
+
+            let mut r = Record::new();
+            r.set_record_type(RecordType::TXT)
+                .set_ttl(config.answer_ttl)
+                .set_data(Some(RData::TXT(TXT::new(txt))));
+            r
+        });
+
+    let mut records: Vec<Record> = records.collect();
+    for _ in 0..config.decoy_records {
+        let at = rand::thread_rng().gen_range(0..=records.len());
+        records.insert(at, decoy(config));
+    }
+    if config.shuffle_answers {
+        records.shuffle(&mut rand::thread_rng());
+    }
+
+    let message = |records: Vec<Record>| {
+        let mut msg = Message::new();
+        msg.set_id(id).set_message_type(MessageType::Response);
+        match config.section {
+            Section::Answer => msg.add_answers(records),
+            Section::Additional => msg.add_additionals(records),
+        };
+
+        if let Some((index, count)) = fragment {
+            let name = Name::from_ascii(format!("{}.{}.", index, count)).ok()?;
+            msg.add_query(Query::query(name, RecordType::TXT));
+        }
+
+        match msg.to_vec() {
+            // records past 65535 bytes are left out, marking it truncated rather than failing
+            Ok(v) if Header::read(&mut BinDecoder::new(&v)).is_ok_and(|h| h.truncated()) => {
+                warn!("message {} doesn't fit in 65535 bytes", id);
+                None
+            }
+            Ok(v) => Some(v),
+            Err(err) => {
+                warn!("{}", err);
+                None
+            }
+        }
+    };
+
+    let mut v = message(records.clone())?;
+
+    // padded with a decoy, unless the shortest one would go over --max-size
+    let max_size = usize::from(config.max_size).min(MAX_MESSAGE);
+    let floor = usize::from(config.min_response_bytes).min(max_size);
+    if v.len() < floor {
+        let at = rand::thread_rng().gen_range(0..=records.len());
+        records.insert(at, padding(floor - v.len(), config));
+        let padded = message(records)?;
+        if padded.len() <= max_size {
+            v = padded;
+        }
+    }
+    Some(Bytes::from(v))
+}
+
+/// "<index>.<count>." question of a fragment, if any
+fn fragment_of(msg: &Message) -> Option<(usize, usize)> {
+    let mut labels = msg.queries().first()?.name().iter().map(|l| {
+        std::str::from_utf8(l)
+            .ok()
+            .and_then(|l| l.parse::<usize>().ok())
+    });
+
+    match (labels.next(), labels.next()) {
+        (Some(Some(index)), Some(Some(count))) => Some((index, count)),
+        _ => None,
+    }
+}
+
+/// fragments of replies still being received, by message id, parity fragments last
+pub struct Reassembly {
+    timeout: Duration,
+    fec_group: Option<usize>,
+    partial: HashMap<u16, (Instant, Vec<Option<Bytes>>)>,
+    /// ids completed before all their parity came in, whose late parity is ignored
+    done: HashMap<u16, Instant>,
+    /// bytes held for `partial`, kept within `max_pending`
+    pending: usize,
+    max_pending: usize,
+}
+
+/// bytes held for one reply, its fragments and a slot for each fragment expected
+fn bytes_of(parts: &[Option<Bytes>]) -> usize {
+    std::mem::size_of_val(parts) + parts.iter().flatten().map(Bytes::len).sum::<usize>()
+}
+
+impl Reassembly {
+    pub fn new(timeout: Duration, fec_group: Option<u8>, max_pending: usize) -> Self {
+        Reassembly {
+            timeout,
+            fec_group: fec_group.map(usize::from),
+            partial: HashMap::new(),
+            done: HashMap::new(),
+            pending: 0,
+            max_pending,
+        }
+    }
+
+    /// returns the whole payload once every fragment of `id` is in
+    fn push(
+        &mut self,
+        id: u16,
+        index: usize,
+        count: usize,
+        b: Bytes,
+        stats: &Stats,
+    ) -> Option<Bytes> {
+        let now = Instant::now();
+        let held = &mut self.pending;
+        self.partial.retain(|_, (since, parts)| {
+            let keep = now.duration_since(*since) < self.timeout;
+            if !keep {
+                *held -= bytes_of(parts);
+            }
+            keep
+        });
+        self.done
+            .retain(|_, since| now.duration_since(*since) < self.timeout);
+
+        if count > MAX_FRAGMENTS {
+            warn!("invalid fragment {}/{} of message {}", index, count, id);
+            return None;
+        }
+        let total = count + self.fec_group.map_or(0, |group| count.div_ceil(group));
+        if index >= total {
+            warn!("invalid fragment {}/{} of message {}", index, count, id);
+            return None;
+        }
+
+        // late parity of a finished message is dropped, a data fragment means the id
+        // was reused for a new one
+        if index >= count && self.done.contains_key(&id) {
+            return None;
+        }
+        self.done.remove(&id);
+
+        // the oldest replies make room, whoever sends fragments that never complete; a new
+        // reply costs its slots too, however small its fragments
+        let slots = total * std::mem::size_of::<Option<Bytes>>();
+        loop {
+            let cost = b.len()
+                + if self.partial.contains_key(&id) {
+                    0
+                } else {
+                    slots
+                };
+            if self.pending + cost <= self.max_pending {
+                break;
+            }
+
+            let Some(oldest) = self
+                .partial
+                .iter()
+                .min_by_key(|(_, (since, _))| *since)
+                .map(|(id, _)| *id)
+            else {
+                debug!("dropped message {}, over --max-pending-bytes", id);
+                stats.capped.fetch_add(1, Ordering::Relaxed);
+                return None;
+            };
+            let (_, parts) = self.partial.remove(&oldest)?;
+            self.pending -= bytes_of(&parts);
+            debug!("dropped message {}, over --max-pending-bytes", oldest);
+            stats.capped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let before = self
+            .partial
+            .get(&id)
+            .map_or(0, |(_, parts)| bytes_of(parts));
+        let (_, parts) = self
+            .partial
+            .entry(id)
+            .or_insert_with(|| (now, vec![None; total]));
+        if parts.len() != total {
+            warn!("fragment count of message {} changed", id);
+            return None;
+        }
+        parts[index] = Some(b);
+
+        if let Some(group) = self.fec_group {
+            recover(parts, count, group);
+        }
+        self.pending = self.pending - before + bytes_of(parts);
+
+        if parts[..count].iter().all(Option::is_some) {
+            let (_, parts) = self.partial.remove(&id)?;
+            self.pending -= bytes_of(&parts);
+            if parts[count..].iter().any(Option::is_none) {
+                self.done.insert(id, now);
+            }
+            Some(parts.into_iter().take(count).flatten().flatten().collect())
+        } else {
+            None
+        }
+    }
+}
+
+/// rebuilds the fragment missing from each group whose parity is in
+fn recover(parts: &mut [Option<Bytes>], count: usize, group: usize) {
+    for (g, start) in (0..count).step_by(group).enumerate() {
+        let members = start..min(start + group, count);
+        let missing: Vec<usize> = members.clone().filter(|&i| parts[i].is_none()).collect();
+        let (&[lost], Some(p)) = (missing.as_slice(), &parts[count + g]) else {
+            continue;
+        };
+        let Some(width) = p.len().checked_sub(PARITY_LEN) else {
+            continue;
+        };
+
+        let others: Vec<&[u8]> = members.filter_map(|i| parts[i].as_deref()).collect();
+        let rebuilt: Vec<u8> = parity(&others, width)
+            .iter()
+            .zip(p.iter())
+            .map(|(a, b)| a ^ b)
+            .collect();
+
+        let len = usize::from(u16::from_be_bytes([rebuilt[0], rebuilt[1]]));
+        if len > width {
+            warn!(
+                "parity of fragments {}..{} doesn't add up",
+                start,
+                start + group
+            );
+            continue;
+        }
+        debug!("rebuilt lost fragment {}/{}", lost, count);
+        parts[lost] = Some(Bytes::copy_from_slice(
+            &rebuilt[PARITY_LEN..PARITY_LEN + len],
+        ));
+    }
+}
+
+/// `buf` as a message, as strictly as `--parse-strict` or `--parse-lenient` say
+fn parse(buf: &[u8], config: &Config) -> ProtoResult<Message> {
+    if config.parse_lenient {
+        return salvage(buf);
+    }
+
+    let mut decoder = BinDecoder::new(buf);
+    let msg = Message::read(&mut decoder)?;
+    if config.parse_strict {
+        if !decoder.is_empty() {
+            return Err(format!(
+                "{} bytes after the last record of message {}",
+                decoder.len(),
+                msg.id()
+            )
+            .into());
+        }
+        if msg.message_type() != MessageType::Response || msg.op_code() != OpCode::Query {
+            return Err(format!("message {} is not a query response", msg.id()).into());
+        }
+    }
+    Ok(msg)
+}
+
+/// the records of `buf` up to the first one that doesn't parse, whatever the header counts
+/// beyond that
+fn salvage(buf: &[u8]) -> ProtoResult<Message> {
+    let mut decoder = BinDecoder::new(buf);
+    let header = Header::read(&mut decoder)?;
+    let queries = Message::read_queries(&mut decoder, header.query_count().into())?;
+
+    let mut records = vec![];
+    while !decoder.is_empty() {
+        match Record::read(&mut decoder) {
+            Ok(r) => records.push(r),
+            Err(err) => {
+                debug!(
+                    "salvaged {} records of message {}: {}",
+                    records.len(),
+                    header.id(),
+                    err
+                );
+                break;
+            }
+        }
+    }
+
+    // sections are told apart by their counts, as far as the records go
+    let mut records = records.into_iter();
+    let answers: Vec<_> = records
+        .by_ref()
+        .take(header.answer_count().into())
+        .collect();
+    let name_servers: Vec<_> = records
+        .by_ref()
+        .take(header.name_server_count().into())
+        .collect();
+
+    let mut msg = Message::new();
+    msg.set_header(header)
+        .add_queries(queries)
+        .add_answers(answers)
+        .add_name_servers(name_servers)
+        .add_additionals(records);
+    Ok(msg)
+}
+
+/// splits the leading index string off an answer's strings
+fn split_index(strings: &[Box<[u8]>]) -> Option<(usize, &[Box<[u8]>])> {
+    let (index, rest) = strings.split_first()?;
+    let index = std::str::from_utf8(index).ok()?.parse().ok()?;
+    Some((index, rest))
+}
+
+/// the datagram `buf` carries, or completes with the fragments before it; every message
+/// that parses counts as downstream wire bytes, whether or not it completes one
+pub fn dns_reply_decode(
+    buf: &[u8],
+    reassembly: &mut Reassembly,
+    config: &Config,
+    stats: &Stats,
+) -> Option<Bytes> {
+    let msg = match parse(buf, config) {
+        Ok(msg) => msg,
+        Err(err) => {
+            warn!("{}", err);
+            return None;
+        }
+    };
+    stats.carried(DOWN, 0, buf.len());
+
+    // the path cut the reply short, what's left can't be trusted
+    if msg.truncated() {
+        warn!(
+            "message {} was truncated on the way, the server's --max-size is too big for this path",
+            msg.id()
+        );
+        return None;
+    }
+
+    let records = match config.section {
+        Section::Answer => msg.answers(),
+        Section::Additional => msg.additionals(),
+    };
+
+    // resolvers may add other records, e.g. a CNAME chain, or group character-strings
+    // into records differently; only the order of the strings themselves counts
+    let mut answers: Vec<&[Box<[u8]>]> = records
+        .iter()
+        .filter_map(|rec| match rec.data() {
+            Some(RData::TXT(txt)) => Some(txt.txt_data()),
+            _ => None,
+        })
+        .collect();
+    if answers.len() < records.len() {
+        debug!(
+            "skipped {} records other than TXT in message {}",
+            records.len() - answers.len(),
+            msg.id()
+        );
+    }
+
+    // an empty datagram is sent as an empty string, and an index is digits
+    let txts = answers.len();
+    answers.retain(|strings| {
+        strings
+            .first()
+            .is_none_or(|s| s.first() != Some(&DECOY_MARKER))
+    });
+    if answers.len() < txts {
+        debug!(
+            "skipped {} decoy records in message {}",
+            txts - answers.len(),
+            msg.id()
+        );
+    }
+
+    // not even the empty string an empty datagram is sent as, nothing to forward
+    if answers.is_empty() {
+        debug!(
+            "no TXT answers in message {}, {:?}",
+            msg.id(),
+            msg.response_code()
+        );
+        stats.empty.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+
+    if config.answer_order == AnswerOrder::Indexed {
+        let mut indexed = match answers
+            .into_iter()
+            .map(split_index)
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(indexed) => indexed,
+            None => {
+                warn!("answer without index in message {}", msg.id());
+                return None;
+            }
+        };
+        // stable, the first of duplicates stays first
+        indexed.sort_by_key(|(i, _)| *i);
+        let received = indexed.len();
+        indexed.dedup_by_key(|(i, _)| *i);
+        if indexed.len() < received {
+            if config.duplicate_answers == Duplicates::Reject {
+                warn!("duplicate answers in message {}", msg.id());
+                return None;
+            }
+            debug!(
+                "dropped {} duplicate answers in message {}",
+                received - indexed.len(),
+                msg.id()
+            );
+        }
+        answers = indexed.into_iter().map(|(_, strings)| strings).collect();
+    }
+
+    // trust-dns has allocated the strings already, this spares joining and decoding them
+    let txt_bytes: usize = answers.iter().flat_map(|a| a.iter()).map(|s| s.len()).sum();
+    if txt_bytes > max_txt_bytes(config) {
+        debug!(
+            "dropped message {} of {} TXT bytes, over --max-txt-bytes",
+            msg.id(),
+            txt_bytes
+        );
+        stats.capped.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+
+    let s: Vec<u8> = answers
+        .into_iter()
+        .flatten()
+        .flat_map(|s| s.iter())
+        .copied()
+        .collect();
+
+    let b = match base64::decode(s) {
+        Ok(b) => Bytes::from(b),
+        Err(err) => {
+            warn!("{}", err);
+            return None;
+        }
+    };
+
+    match fragment_of(&msg) {
+        Some((index, count)) => {
+            debug!("fragment {}/{} of message {}", index, count, msg.id());
+            reassembly.push(msg.id(), index, count, b, stats)
+        }
+        None => Some(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, payload};
+    use rand::{rngs::StdRng, SeedableRng};
+    use trust_dns_proto::op::ResponseCode;
+
+    /// the last datagram the client gets out of `msgs`, taken in this order
+    fn decode<'a>(msgs: impl IntoIterator<Item = &'a Bytes>, config: &Config) -> Option<Bytes> {
+        let mut reassembly = Reassembly::new(
+            Duration::from_secs(config.timeout),
+            config.fec_group,
+            config.max_pending_bytes,
+        );
+        let stats = Stats::default();
+        msgs.into_iter()
+            .filter_map(|msg| dns_reply_decode(msg, &mut reassembly, config, &stats))
+            .last()
+    }
+
+    /// data fragments of a split reply, the parity ones come after them
+    fn count(msgs: &[Bytes]) -> usize {
+        fragment_of(&Message::from_vec(&msgs[0]).unwrap())
+            .unwrap()
+            .1
+    }
+
+    /// 11 fragments of 340 bytes but the last, in groups of 3, 3, 3 and 2
+    fn fec() -> (Config, Vec<u8>, Vec<Bytes>) {
+        let config = config(&["--max-size", "512", "--fec-group", "3"]);
+        let buf = payload(3500);
+        let msgs = dns_reply_encode(&buf, &config);
+        assert_eq!(count(&msgs), 11);
+        assert_eq!(msgs.len(), 15);
+        (config, buf, msgs)
+    }
+
+    #[test]
+    fn fec_rebuilds_one_lost_fragment_per_group() {
+        let (config, buf, msgs) = fec();
+        // the last of each group, the short last fragment among them
+        let lost = [2, 5, 8, 10];
+        let kept = msgs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !lost.contains(i))
+            .map(|(_, msg)| msg);
+        assert_eq!(decode(kept, &config).as_deref(), Some(&buf[..]));
+    }
+
+    #[test]
+    fn fec_needs_no_parity_when_nothing_is_lost() {
+        let (config, buf, msgs) = fec();
+        assert_eq!(decode(&msgs[..11], &config).as_deref(), Some(&buf[..]));
+    }
+
+    #[test]
+    fn fec_rebuilds_fragments_whatever_the_order() {
+        let (config, buf, msgs) = fec();
+        let kept = msgs
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(i, _)| ![0, 4, 6, 9].contains(i))
+            .map(|(_, msg)| msg);
+        assert_eq!(decode(kept, &config).as_deref(), Some(&buf[..]));
+    }
+
+    #[test]
+    fn fec_cannot_rebuild_two_lost_fragments_of_a_group() {
+        let (config, _, msgs) = fec();
+        let kept = msgs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| ![3, 4].contains(i))
+            .map(|(_, msg)| msg);
+        assert_eq!(decode(kept, &config), None);
+    }
+
+    /// the strings of the TXT records of `msg`, whichever section carries them
+    fn strings(msg: &Bytes) -> Vec<u8> {
+        let msg = Message::from_vec(msg).unwrap();
+        msg.answers()
+            .iter()
+            .chain(msg.additionals())
+            .filter_map(|r| match r.data() {
+                Some(RData::TXT(txt)) => Some(txt.txt_data().concat()),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    #[test]
+    fn replies_stay_within_max_size() {
+        for max in [512, 1232, 4096] {
+            let config = config(&["--max-size", &max.to_string()]);
+            let mut split = false;
+            for len in 0..=max * 2 {
+                let buf = payload(len);
+                let msgs = dns_reply_encode(&buf, &config);
+                assert!(msgs.iter().all(|msg| msg.len() <= max), "{} bytes", len);
+                assert_eq!(decode(&msgs, &config).as_deref(), Some(&buf[..]));
+
+                // once too big for one message, longer ones are as well
+                if msgs.len() == 1 {
+                    assert!(!split, "{} bytes", len);
+                } else {
+                    if !split {
+                        let largest = dns_reply_encode(&payload(len - 1), &config);
+                        assert!(largest[0].len() > max - 8, "{} bytes", largest[0].len());
+                    }
+                    split = true;
+                }
+            }
+            assert!(split);
+        }
+    }
+
+    #[test]
+    fn replies_stay_within_a_udp_payload() {
+        let config = config(&[]);
+        assert_eq!(dns_reply_encode(&payload(45_000), &config).len(), 1);
+
+        let buf = payload(60_000);
+        let msgs = dns_reply_encode(&buf, &config);
+        assert!(msgs.len() > 1);
+        assert!(msgs.iter().all(|msg| msg.len() <= MAX_MESSAGE));
+        assert_eq!(decode(&msgs, &config).as_deref(), Some(&buf[..]));
+    }
+
+    #[test]
+    fn fragments_are_whole_base64() {
+        for chunk in ["1", "7", "254", "255"] {
+            let config = config(&["--max-size", "512", "--txt-chunk", chunk]);
+            for len in [1000, 1001, 1002] {
+                let buf = payload(len);
+                let msgs = dns_reply_encode(&buf, &config);
+                assert!(msgs.len() > 1);
+                for msg in &msgs {
+                    assert!(base64::decode(strings(msg)).is_ok());
+                }
+                assert_eq!(decode(&msgs, &config).as_deref(), Some(&buf[..]));
+            }
+        }
+    }
+
+    #[test]
+    fn both_answer_orders_decode() {
+        for order in ["ordered", "indexed"] {
+            for strings in ["1", "3"] {
+                let config = config(&[
+                    "--answer-order",
+                    order,
+                    "--txt-strings",
+                    strings,
+                    "--txt-chunk",
+                    "10",
+                ]);
+                for len in [0, 1, 1000] {
+                    let buf = payload(len);
+                    let msgs = dns_reply_encode(&buf, &config);
+                    assert_eq!(decode(&msgs, &config).as_deref(), Some(&buf[..]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn only_indexed_answers_survive_reordering() {
+        let buf = payload(1000);
+        for (order, survives) in [("ordered", false), ("indexed", true)] {
+            let config = config(&[
+                "--answer-order",
+                order,
+                "--txt-chunk",
+                "10",
+                "--shuffle-answers",
+            ]);
+            let msgs = dns_reply_encode(&buf, &config);
+            assert_eq!(
+                decode(&msgs, &config).as_deref() == Some(&buf[..]),
+                survives
+            );
+        }
+    }
+
+    #[test]
+    fn replies_in_the_additional_section() {
+        let buf = payload(3000);
+        for max_size in ["65535", "512"] {
+            let additional = config(&["--section", "additional", "--max-size", max_size]);
+            let msgs = dns_reply_encode(&buf, &additional);
+            for msg in &msgs {
+                assert!(Message::from_vec(msg).unwrap().answers().is_empty());
+                assert!(base64::decode(strings(msg)).is_ok());
+            }
+            assert_eq!(decode(&msgs, &additional).as_deref(), Some(&buf[..]));
+
+            // a client looking in the other section finds nothing, either way round
+            let answer = config(&["--max-size", max_size]);
+            assert_eq!(decode(&msgs, &answer), None);
+            let msgs = dns_reply_encode(&buf, &answer);
+            assert_eq!(decode(&msgs, &additional), None);
+        }
+    }
+
+    /// `msg` with its strings regrouped `per_record` to a TXT record, behind a CNAME as a
+    /// resolver following an alias returns them
+    fn regrouped(msg: &Bytes, per_record: usize) -> Bytes {
+        let mut msg = Message::from_vec(msg).unwrap();
+        let answers = msg.take_answers();
+        let strings: Vec<&[u8]> = answers
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::TXT(txt)) => Some(txt.txt_data()),
+                _ => None,
+            })
+            .flatten()
+            .map(|s| &s[..])
+            .collect();
+
+        let alias = Name::from_ascii("alias.").unwrap();
+        msg.add_answer(Record::from_rdata(
+            Name::root(),
+            0,
+            RData::CNAME(alias.clone()),
+        ));
+        msg.add_answers(strings.chunks(per_record).map(|txt| {
+            Record::from_rdata(alias.clone(), 0, RData::TXT(TXT::from_bytes(txt.to_vec())))
+        }));
+        Bytes::from(msg.to_vec().unwrap())
+    }
+
+    #[test]
+    fn regrouped_answers_decode() {
+        let config = config(&[
+            "--max-size",
+            "512",
+            "--txt-chunk",
+            "10",
+            "--txt-strings",
+            "3",
+        ]);
+        for len in [0, 1, 1000] {
+            let buf = payload(len);
+            let msgs = dns_reply_encode(&buf, &config);
+            for per_record in [1, 2, 5, 255] {
+                let msgs: Vec<Bytes> = msgs.iter().map(|m| regrouped(m, per_record)).collect();
+                assert_eq!(decode(&msgs, &config).as_deref(), Some(&buf[..]));
+            }
+        }
+    }
+
+    /// `msg` with its answer `at` repeated right after it, as some resolvers do
+    fn repeated(msg: &Bytes, at: usize) -> Bytes {
+        let mut msg = Message::from_vec(msg).unwrap();
+        let mut answers = msg.take_answers();
+        answers.insert(at + 1, answers[at].clone());
+        msg.add_answers(answers);
+        Bytes::from(msg.to_vec().unwrap())
+    }
+
+    #[test]
+    fn duplicate_answers() {
+        let buf = payload(1000);
+        for (args, decodes) in [
+            (&["--answer-order", "indexed"][..], true),
+            (
+                &["--answer-order", "indexed", "--duplicate-answers", "reject"],
+                false,
+            ),
+            // nothing to tell a repeat by
+            (&["--answer-order", "ordered"], false),
+        ] {
+            let config = config(args);
+            let msgs = dns_reply_encode(&buf, &config);
+            let msg = repeated(&msgs[0], 2);
+            assert_eq!(
+                decode([&msg], &config).as_deref() == Some(&buf[..]),
+                decodes
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let buf = payload(1000);
+        let msg = dns_reply_encode(&buf, &config(&[])).remove(0).to_vec();
+
+        let trailing = [&msg[..], &[0, 0, 0]].concat();
+        let mut overcounted = msg.clone();
+        overcounted[7] += 1;
+        let mut query = msg.clone();
+        query[2] &= !0x80;
+
+        // as decoded by default, with --parse-strict and with --parse-lenient
+        for (msg, decodes) in [
+            (&msg, [true, true, true]),
+            (&trailing, [true, false, true]),
+            (&overcounted, [false, false, true]),
+            (&query, [true, false, true]),
+            (&msg[..5].to_vec(), [false, false, false]),
+        ] {
+            for (args, decodes) in [&[][..], &["--parse-strict"], &["--parse-lenient"]]
+                .into_iter()
+                .zip(decodes)
+            {
+                let msg = Bytes::copy_from_slice(msg);
+                let decoded = decode([&msg], &config(args));
+                assert_eq!(decoded.as_deref() == Some(&buf[..]), decodes, "{:?}", args);
+            }
+        }
+    }
+
+    #[test]
+    fn decoys_are_skipped() {
+        let buf = payload(3000);
+        for max_size in ["65535", "1232"] {
+            let decoyed = config(&["--decoy-records", "3", "--max-size", max_size]);
+            let msgs = dns_reply_encode(&buf, &decoyed);
+            for msg in &msgs {
+                assert!(msg.len() <= usize::from(decoyed.max_size));
+                let decoys = Message::from_vec(msg)
+                    .unwrap()
+                    .answers()
+                    .iter()
+                    .filter(|r| match r.data() {
+                        Some(RData::TXT(txt)) => txt.txt_data()[0][0] == DECOY_MARKER,
+                        _ => false,
+                    })
+                    .count();
+                assert_eq!(decoys, 3);
+            }
+
+            // the client needs no --decoy-records
+            let plain = config(&["--max-size", max_size]);
+            assert_eq!(decode(&msgs, &plain).as_deref(), Some(&buf[..]));
+        }
+    }
+
+    #[test]
+    fn fragments_hold_the_largest_datagram() {
+        let crowded = [
+            "--max-size",
+            "512",
+            "--txt-chunk",
+            "1",
+            "--answer-order",
+            "indexed",
+            "--decoy-records",
+            "5",
+        ];
+        for fec in [&[][..], &["--fec-group", "1"]] {
+            let config = config(&[&crowded[..], fec].concat());
+            assert!(!fits(&config));
+            assert!(!crate::check::validate(&config).is_empty());
+
+            // split into no more fragments than a client takes, or not at all
+            let data = fragment_data(512 - decoys_size(&config), &config).unwrap();
+            let buf = payload(data * MAX_FRAGMENTS);
+            let msgs = dns_reply_encode(&buf, &config);
+            assert_eq!(count(&msgs), MAX_FRAGMENTS);
+            assert_eq!(decode(&msgs, &config).as_deref(), Some(&buf[..]));
+            assert!(dns_reply_encode(&payload(data * MAX_FRAGMENTS + 1), &config).is_empty());
+            assert!(dns_reply_encode(&payload(65_000), &config).is_empty());
+        }
+
+        // a little more room for data is enough
+        let config = config(&[&crowded[..6], &["--decoy-records", "1"]].concat());
+        assert!(fits(&config));
+        let buf = payload(MAX_DATAGRAM);
+        assert_eq!(
+            decode(&dns_reply_encode(&buf, &config), &config).as_deref(),
+            Some(&buf[..])
+        );
+    }
+
+    #[test]
+    fn padded_replies_decode() {
+        let config = config(&["--min-response-bytes", "1000"]);
+        for len in [0, 1, 100, 700, 1000] {
+            let buf = payload(len);
+            let msgs = dns_reply_encode(&buf, &config);
+            assert!(msgs[0].len() >= 1000);
+            assert_eq!(decode(&msgs, &config).as_deref(), Some(&buf[..]));
+        }
+    }
+
+    #[test]
+    fn replies_without_answers_are_counted() {
+        let config = config(&[]);
+        let mut reassembly = Reassembly::new(Duration::from_secs(60), None, 1 << 20);
+        let stats = Stats::default();
+        for (code, empty) in [(ResponseCode::NoError, 1), (ResponseCode::NXDomain, 2)] {
+            let mut msg = Message::new();
+            msg.set_id(1)
+                .set_message_type(MessageType::Response)
+                .set_response_code(code)
+                .add_query(Query::query(Name::root(), RecordType::TXT));
+            let msg = msg.to_vec().unwrap();
+            assert_eq!(
+                dns_reply_decode(&msg, &mut reassembly, &config, &stats),
+                None
+            );
+            assert_eq!(stats.empty.load(Ordering::Relaxed), empty);
+        }
+    }
+
+    #[test]
+    fn unserializable_message_is_dropped() {
+        // base64 of it doesn't fit the 65535 bytes of a message
+        assert_eq!(dns_message(1, None, &payload(60_000), &config(&[])), None);
+    }
+
+    /// a path between server and client that loses messages, repeats some and reorders those
+    /// of neighbouring replies, taking `delay` per message
+    struct LossyPath {
+        rng: StdRng,
+        /// chance of losing each message, or one of each FEC group with `per_group`
+        loss: f64,
+        per_group: bool,
+        repeat: f64,
+        delay: Duration,
+    }
+
+    impl LossyPath {
+        /// what the client delivers of `bufs`, sent as replies with ids of their own
+        async fn deliver(&mut self, bufs: &[Vec<u8>], config: &mut Config) -> Vec<Bytes> {
+            let mut reassembly = Reassembly::new(
+                Duration::from_secs(config.timeout),
+                config.fec_group,
+                config.max_pending_bytes,
+            );
+            let stats = Stats::default();
+
+            let mut delivered = vec![];
+            for (n, pair) in bufs.chunks(2).enumerate() {
+                let mut msgs = vec![];
+                for (i, buf) in pair.iter().enumerate() {
+                    config.fixed_id = Some((2 * n + i) as u16);
+                    let reply = dns_reply_encode(buf, config);
+                    let lost = self.lost(&reply, config);
+                    for (j, msg) in reply.into_iter().enumerate() {
+                        if lost.contains(&j) {
+                            continue;
+                        }
+                        if self.rng.gen_bool(self.repeat) {
+                            msgs.push(msg.clone());
+                        }
+                        msgs.push(msg);
+                    }
+                }
+                msgs.shuffle(&mut self.rng);
+
+                for msg in msgs {
+                    tokio::time::advance(self.delay).await;
+                    delivered.extend(dns_reply_decode(&msg, &mut reassembly, config, &stats));
+                }
+            }
+            delivered
+        }
+
+        /// indices of the messages of `reply` to lose
+        fn lost(&mut self, reply: &[Bytes], config: &Config) -> Vec<usize> {
+            let (Some(group), true) = (config.fec_group.map(usize::from), self.per_group) else {
+                return (0..reply.len())
+                    .filter(|_| self.rng.gen_bool(self.loss))
+                    .collect();
+            };
+            if reply.len() == 1 {
+                return vec![];
+            }
+
+            // a group's fragments and its parity, one of them at most
+            let count = count(reply);
+            let mut lost = vec![];
+            for g in 0..count.div_ceil(group) {
+                if self.rng.gen_bool(self.loss) {
+                    let members: Vec<usize> = (g * group..min((g + 1) * group, count))
+                        .chain([count + g])
+                        .collect();
+                    lost.extend(members.choose(&mut self.rng));
+                }
+            }
+            lost
+        }
+    }
+
+    fn payloads(rng: &mut StdRng, n: usize) -> Vec<Vec<u8>> {
+        (0..n)
+            .map(|_| {
+                let mut buf = vec![0_u8; rng.gen_range(0..6000)];
+                rng.fill(&mut buf[..]);
+                buf
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fec_delivers_everything_within_its_budget() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let bufs = payloads(&mut rng, 100);
+        let mut path = LossyPath {
+            rng,
+            loss: 0.5,
+            per_group: true,
+            repeat: 0.2,
+            delay: Duration::from_millis(5),
+        };
+
+        let mut config = config(&["--max-size", "512", "--fec-group", "4"]);
+        let delivered = path.deliver(&bufs, &mut config).await;
+        for buf in &bufs {
+            assert!(
+                delivered.iter().any(|d| d == &buf[..]),
+                "{} bytes",
+                buf.len()
+            );
+        }
+        assert!(delivered
+            .iter()
+            .all(|d| bufs.iter().any(|buf| d == &buf[..])));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn loss_never_corrupts_what_is_delivered() {
+        for fec in [None, Some("2")] {
+            let mut rng = StdRng::seed_from_u64(2);
+            let bufs = payloads(&mut rng, 100);
+            let mut path = LossyPath {
+                rng,
+                loss: 0.1,
+                per_group: false,
+                repeat: 0.2,
+                delay: Duration::from_millis(5),
+            };
+
+            let mut args = vec!["--max-size", "512"];
+            args.extend(fec.iter().flat_map(|group| ["--fec-group", group]));
+            let mut config = config(&args);
+            let delivered = path.deliver(&bufs, &mut config).await;
+            assert!(!delivered.is_empty());
+            assert!(delivered
+                .iter()
+                .all(|d| bufs.iter().any(|buf| d == &buf[..])));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn incomplete_replies_expire() {
+        let mut config = config(&["--max-size", "512", "--timeout", "5"]);
+        let mut reassembly = Reassembly::new(Duration::from_secs(5), None, 1 << 20);
+        let stats = Stats::default();
+
+        config.fixed_id = Some(1);
+        let msgs = dns_reply_encode(&payload(3000), &config);
+        for msg in &msgs[1..] {
+            assert_eq!(
+                dns_reply_decode(msg, &mut reassembly, &config, &stats),
+                None
+            );
+        }
+        assert!(reassembly.partial.contains_key(&1));
+
+        // anything coming in clears what timed out
+        tokio::time::advance(Duration::from_secs(6)).await;
+        config.fixed_id = Some(2);
+        let other = dns_reply_encode(&payload(3000), &config);
+        dns_reply_decode(&other[0], &mut reassembly, &config, &stats);
+        assert_eq!(reassembly.partial.keys().collect::<Vec<_>>(), [&2]);
+        assert_eq!(reassembly.pending, bytes_of(&reassembly.partial[&2].1));
+
+        // too late to complete the first reply
+        assert_eq!(
+            dns_reply_decode(&msgs[0], &mut reassembly, &config, &stats),
+            None
+        );
+    }
+
+    #[test]
+    fn empty_fragments_count_against_max_pending_bytes() {
+        let config = config(&[]);
+        let max_pending = 1 << 20;
+        let mut reassembly = Reassembly::new(Duration::from_secs(60), None, max_pending);
+        let stats = Stats::default();
+
+        // first of as many empty fragments as can be, under ids of their own
+        for id in 0..100 {
+            let msg = dns_message(id, Some((0, MAX_FRAGMENTS)), &[], &config).unwrap();
+            assert_eq!(
+                dns_reply_decode(&msg, &mut reassembly, &config, &stats),
+                None
+            );
+            assert!(reassembly.pending <= max_pending);
+        }
+        let slots = MAX_FRAGMENTS * std::mem::size_of::<Option<Bytes>>();
+        assert_eq!(reassembly.partial.len(), max_pending / slots);
+        assert!(stats.capped.load(Ordering::Relaxed) > 0);
+
+        // the newest are kept
+        assert!(reassembly.partial.contains_key(&99));
+    }
+
+    #[test]
+    fn oversized_txt_is_dropped() {
+        let buf = payload(3000);
+        let msgs = dns_reply_encode(&buf, &config(&[]));
+        let stats = Stats::default();
+        for (max, decodes) in [("4000", true), ("3999", false)] {
+            let config = config(&["--max-txt-bytes", max]);
+            let mut reassembly = Reassembly::new(Duration::from_secs(60), None, 1 << 20);
+            let decoded = dns_reply_decode(&msgs[0], &mut reassembly, &config, &stats);
+            assert_eq!(decoded.is_some(), decodes);
+        }
+        assert_eq!(stats.capped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn txt_bytes_are_capped_by_max_size() {
+        let buf = payload(3000);
+        let msgs = dns_reply_encode(&buf, &config(&[]));
+        assert_eq!(msgs.len(), 1);
+        let stats = Stats::default();
+        // the client expecting messages of --max-size 1232 at most
+        for (max_size, decodes) in [("65535", true), ("4100", true), ("1232", false)] {
+            let config = config(&["-c", "--max-size", max_size]);
+            let mut reassembly = Reassembly::new(Duration::from_secs(60), None, 1 << 20);
+            let decoded = dns_reply_decode(&msgs[0], &mut reassembly, &config, &stats);
+            assert_eq!(decoded.is_some(), decodes, "--max-size {}", max_size);
+        }
+        assert_eq!(stats.capped.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,199 @@
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+
+use clap::ValueEnum;
+use tokio::time::Duration;
+
+use bytes::Bytes;
+
+use crate::{
+    dns::{dns_reply_decode, dns_reply_encode, fits, Reassembly},
+    hex, Codec, Config, Stats,
+};
+
+/// how `--encode` and `--decode` write and read DNS messages and datagrams, one per line
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DumpFormat {
+    Hex,
+    Base64,
+}
+
+impl DumpFormat {
+    fn name(self) -> &'static str {
+        match self {
+            DumpFormat::Hex => "hex",
+            DumpFormat::Base64 => "base64",
+        }
+    }
+
+    fn write(self, buf: &[u8]) -> String {
+        match self {
+            DumpFormat::Hex => hex(buf),
+            DumpFormat::Base64 => base64::encode(buf),
+        }
+    }
+
+    fn read(self, line: &str) -> Option<Vec<u8>> {
+        match self {
+            DumpFormat::Hex => unhex(line),
+            DumpFormat::Base64 => base64::decode(line).ok(),
+        }
+    }
+}
+
+/// `--encode`: the DNS messages the server sends for the datagram on stdin, as the
+/// codec options say; the datagram itself with `--codec raw-passthrough`
+pub fn encode(config: &Config) -> Result<()> {
+    if config.codec == Codec::Txt && !fits(config) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--max-size leaves fragments too small to carry the largest datagram",
+        ));
+    }
+
+    let mut datagram = Vec::new();
+    std::io::stdin().read_to_end(&mut datagram)?;
+    encode_to(&datagram, config, &mut std::io::stdout().lock())
+}
+
+fn encode_to(datagram: &[u8], config: &Config, out: &mut impl Write) -> Result<()> {
+    let msgs = match config.codec {
+        Codec::RawPassthrough => vec![Bytes::copy_from_slice(datagram)],
+        Codec::Txt => dns_reply_encode(datagram, config),
+    };
+    // even an empty datagram takes a message, there's none for one too big to split
+    if msgs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot encode the {} bytes on stdin", datagram.len()),
+        ));
+    }
+
+    for msg in msgs {
+        writeln!(out, "{}", config.dump_format.write(&msg))?;
+    }
+    out.flush()
+}
+
+/// `--decode`: the datagrams carried by the DNS messages on stdin, written to stdout as
+/// they complete, one per line like the messages; each message is a datagram already with
+/// `--codec raw-passthrough`
+pub fn decode(config: &Config) -> Result<()> {
+    decode_to(
+        std::io::stdin().lock(),
+        config,
+        &mut std::io::stdout().lock(),
+    )
+}
+
+fn decode_to(input: impl BufRead, config: &Config, out: &mut impl Write) -> Result<()> {
+    let stats = Stats::default();
+    let mut reassembly = Reassembly::new(
+        Duration::from_secs(config.timeout),
+        config.fec_group,
+        config.max_pending_bytes,
+    );
+
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        // blank lines are left out, but for the empty datagram with raw-passthrough
+        let line = line.trim();
+        if line.is_empty() && config.codec == Codec::Txt {
+            continue;
+        }
+
+        let msg = config.dump_format.read(line).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "line {} is not a message in {}",
+                    n + 1,
+                    config.dump_format.name()
+                ),
+            )
+        })?;
+
+        let datagram = match config.codec {
+            Codec::RawPassthrough => Some(Bytes::from(msg)),
+            Codec::Txt => dns_reply_decode(&msg, &mut reassembly, config, &stats),
+        };
+        if let Some(datagram) = datagram {
+            writeln!(out, "{}", config.dump_format.write(&datagram))?;
+        }
+    }
+    out.flush()
+}
+
+/// bytes of a hex string, `None` if any pair isn't hex or one digit is left over
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    // from_str_radix would take a sign too
+    if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, payload};
+
+    /// what `--decode` writes for what `--encode` wrote for each of `datagrams`
+    fn round_trip(datagrams: &[Vec<u8>], config: &Config) -> Vec<Vec<u8>> {
+        let mut encoded = vec![];
+        for datagram in datagrams {
+            encode_to(datagram, config, &mut encoded).unwrap();
+        }
+        let mut decoded = vec![];
+        decode_to(&encoded[..], config, &mut decoded).unwrap();
+
+        String::from_utf8(decoded)
+            .unwrap()
+            .lines()
+            .map(|line| config.dump_format.read(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decode_reverses_encode() {
+        let datagrams = [payload(100), payload(0), payload(3500)];
+        for format in ["hex", "base64"] {
+            for args in [
+                &[][..],
+                &["--max-size", "512", "--fec-group", "3"],
+                &["--codec", "raw-passthrough"],
+            ] {
+                let config = config(&[&["--dump-format", format][..], args].concat());
+                assert_eq!(round_trip(&datagrams, &config), datagrams, "{:?}", args);
+            }
+        }
+    }
+
+    #[test]
+    fn split_replies_take_a_line_per_message() {
+        let config = config(&["--max-size", "512", "--fec-group", "3"]);
+        let mut encoded = vec![];
+        encode_to(&payload(3500), &config, &mut encoded).unwrap();
+        let lines = String::from_utf8(encoded).unwrap();
+        assert_eq!(lines.lines().count(), 15);
+        assert!(lines.lines().all(|line| unhex(line).is_some()));
+    }
+
+    #[test]
+    fn lines_that_are_no_message_are_refused() {
+        let config = config(&[]);
+        let err = decode_to(&b"00\nxyz\n"[..], &config, &mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unhex_takes_pairs_of_hex_digits() {
+        assert_eq!(unhex(""), Some(vec![]));
+        assert_eq!(unhex("00ff0A1b"), Some(vec![0, 0xff, 0x0a, 0x1b]));
+        for bad in ["0", "abc", "0g", "+1", "-1", "éé", "0é0", "é"] {
+            assert_eq!(unhex(bad), None, "{}", bad);
+        }
+    }
+}
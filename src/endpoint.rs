@@ -0,0 +1,117 @@
+use std::{fmt, io::Result};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+/// a connection accepted by an `Endpoint`
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// listener of the auxiliary HTTP endpoints, on TCP or on a Unix socket
+pub enum Endpoint {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, std::path::PathBuf),
+}
+
+impl Endpoint {
+    /// binds `addr`, a Unix socket path if it has a '/' in it, e.g. "./health.sock",
+    /// otherwise a TCP address
+    pub async fn bind(addr: &str) -> Result<Self> {
+        if !addr.contains('/') {
+            return TcpListener::bind(addr).await.map(Endpoint::Tcp);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::{io::ErrorKind, os::unix::fs::FileTypeExt, os::unix::net::UnixStream};
+
+            // a socket left behind by an earlier run would make the bind fail, one a running
+            // instance still serves on is left to it
+            let path = std::path::PathBuf::from(addr);
+            if std::fs::metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                match UnixStream::connect(&path) {
+                    Ok(_) => {
+                        return Err(std::io::Error::new(
+                            ErrorKind::AddrInUse,
+                            format!("{} is served by a running instance", addr),
+                        ))
+                    }
+                    Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                        std::fs::remove_file(&path)?
+                    }
+                    Err(_) => {}
+                }
+            }
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            Ok(Endpoint::Unix(listener, path))
+        }
+
+        #[cfg(not(unix))]
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Unix sockets are only supported on unix",
+        ))
+    }
+
+    /// next connection, and who it's from for the logs
+    pub async fn accept(&self) -> Result<(Box<dyn Stream>, String)> {
+        match self {
+            Endpoint::Tcp(listener) => {
+                let (stream, from) = listener.accept().await?;
+                Ok((Box::new(stream), from.to_string()))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), path.display().to_string()))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            #[cfg(unix)]
+            Endpoint::Unix(_, path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        if let Endpoint::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unix_sockets_in_use_are_kept() {
+        let path =
+            std::env::temp_dir().join(format!("udp2dns-endpoint-{}.sock", std::process::id()));
+        let addr = path.to_str().unwrap();
+
+        // left behind by a run that didn't clean up
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let running = Endpoint::bind(addr).await.unwrap();
+
+        assert!(Endpoint::bind(addr).await.is_err());
+        assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+        drop(running);
+        assert!(!path.exists());
+    }
+}
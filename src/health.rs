@@ -0,0 +1,81 @@
+use log::debug;
+use std::{
+    io::Result,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    endpoint::{Endpoint, Stream},
+    Destinations, Stats,
+};
+
+/// set once the main loop is serving
+pub type Ready = Arc<AtomicBool>;
+
+/// answers `/healthz` as long as the process runs, `/ready` once `ready` is set,
+/// `/destinations` with how each destination is doing and `/metrics` with the counters
+pub async fn serve(
+    listener: Endpoint,
+    ready: Ready,
+    dsts: Arc<Destinations>,
+    stats: Arc<Stats>,
+) -> Result<()> {
+    loop {
+        let (stream, from) = listener.accept().await?;
+        debug!("health check from {}", from);
+
+        let (ready, dsts, stats) = (ready.clone(), dsts.clone(), stats.clone());
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &ready, &dsts, &stats).await {
+                debug!("health check failed: {}", err);
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: Box<dyn Stream>,
+    ready: &AtomicBool,
+    dsts: &Destinations,
+    stats: &Stats,
+) -> Result<()> {
+    let mut buf = [0_u8; 1024];
+    let mut len = 0;
+
+    while len < buf.len() && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf[len..]).await? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, body) = match path {
+        "/healthz" => ("200 OK", String::from("ok\n")),
+        "/ready" if ready.load(Ordering::Relaxed) => ("200 OK", String::from("ready\n")),
+        "/ready" => ("503 Service Unavailable", String::from("not ready\n")),
+        "/destinations" => ("200 OK", dsts.report()),
+        "/metrics" => ("200 OK", stats.metrics()),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await
+}
@@ -1,196 +1,2312 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use log::{debug, info, warn};
 use std::{
     cmp::min,
     collections::HashMap,
-    io::Result,
-    net::{SocketAddr, ToSocketAddrs},
-    sync::Arc,
+    io::{Error, ErrorKind, Result},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use bytes::Bytes;
 
 use tokio::{
-    net::UdpSocket,
+    net::{lookup_host, UdpSocket},
     select,
     sync::{
-        mpsc::{self, Receiver, Sender},
-        Mutex,
+        mpsc::{self, error::TrySendError, Sender},
+        watch, Mutex,
     },
+    task::JoinSet,
     time::{Duration, Instant},
 };
 
-use trust_dns_proto::{
-    op::Message,
-    rr::{rdata::TXT, RData, Record, RecordType},
-};
+mod check;
+mod control;
+#[cfg(feature = "dns")]
+mod dns;
+#[cfg(feature = "dns")]
+mod dump;
+mod endpoint;
+mod health;
+mod mirror;
+mod once;
+#[cfg(feature = "dns")]
+mod probe;
+mod proxy;
+mod queue;
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Config {
-    listen: String,
-    dst: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// validate addresses, options and privileges, then exit without serving
+    #[arg(long)]
+    check: bool,
+    /// write the DNS messages the server would send for the datagram on stdin, one per line,
+    /// and exit; for comparing with other implementations, no addresses needed. With
+    /// `--codec raw-passthrough` that's the datagram itself
+    #[arg(long, conflicts_with = "decode")]
+    encode: bool,
+    /// read DNS messages as `--encode` writes them from stdin, write the datagrams they carry
+    /// to stdout, one per line the same way, and exit
+    #[arg(long)]
+    decode: bool,
+    /// how `--encode` and `--decode` write and read messages and datagrams
+    #[cfg(feature = "dns")]
+    #[arg(long, value_enum, default_value_t = dump::DumpFormat::Hex)]
+    dump_format: dump::DumpFormat,
+    /// ignored when systemd passes the listening socket
+    #[arg(env = "UDP2DNS_LISTEN", required_unless_present_any = ["encode", "decode"])]
+    listen: Option<String>,
+    /// `--help` leaves out the value from the environment, logs still show the destination
+    #[arg(env = "UDP2DNS_DST", hide_env_values = true, required_unless_present_any = ["encode", "decode"])]
+    dst: Option<String>,
+    /// standby destination, used while `dst` is unreachable
+    #[arg(long)]
+    backup_dst: Option<String>,
+    /// seconds an upstream datagram may go unanswered before `dst` is considered unreachable
+    #[arg(long, default_value_t = 5)]
+    failover_after: u64,
+    /// seconds after failing over to retry `dst`; stays on the backup if unset
+    #[arg(long)]
+    failback_after: Option<u64>,
+    /// seconds to wait for each lookup of a destination
+    #[arg(long, default_value_t = 5)]
+    resolve_timeout: u64,
+    /// lookups retried when resolving a destination fails, e.g. at boot before DNS is up
+    #[arg(long, default_value_t = 3)]
+    resolve_retries: u32,
+    /// seconds between lookups of dst and backup_dst, sessions follow them to new addresses
+    #[arg(long)]
+    re_resolve: Option<u64>,
     #[arg(short, long)]
     client: bool,
+    /// send the datagram on stdin to dst from the listen address, write the reply to stdout
+    /// and exit, with status 2 if none came within --timeout
+    #[arg(long)]
+    once: bool,
     /// can be "debug", "info", or "warn"
     #[arg(short, long, default_value_t = String::from("warn"))]
     loglevel: String,
-    /// in seconds
+    /// in seconds; this and every other session timer run on the monotonic clock, so setting
+    /// the wall clock doesn't end sessions, and time suspended doesn't count
     #[arg(short, long, default_value_t = 60)]
     timeout: u64,
-    /// send and receive queue size
+    /// seconds without upstream traffic before that direction counts as idle, defaults to timeout
+    #[arg(long)]
+    up_idle: Option<u64>,
+    /// seconds without downstream traffic before that direction counts as idle, defaults to timeout;
+    /// a relay stops once both directions are idle
+    #[arg(long)]
+    down_idle: Option<u64>,
+    /// seconds a new session waits for the first reply from `dst` before it is stopped,
+    /// instead of idling out after timeout
+    #[arg(long)]
+    first_response: Option<u64>,
+    /// send and receive queue size, in datagrams; each session also holds a 64 KiB buffer
+    /// for its relay socket, so datagrams from the other side come through whole
     #[arg(short, long, default_value_t = 20)]
     bufsize: usize,
+    /// have the kernel label each IPv6 relay socket's packets with one flow label, hashed from
+    /// its addresses and ports, so ECMP routers keep a session on one path; Linux only, where it
+    /// only matters with net.ipv6.auto_flowlabels at 2, making labels opt-in, as the default 1
+    /// labels every socket already. Nothing to do on IPv4
+    #[arg(long)]
+    flow_label: bool,
+    /// end a session whose relay socket can't take the options asked for, e.g. in a
+    /// container lacking the capabilities, instead of warning and relaying without them
+    #[arg(long)]
+    strict_sockopts: bool,
+    /// what a session's full queue drops
+    #[arg(long, value_enum, default_value_t = queue::Overflow::DropNewest)]
+    overflow: queue::Overflow,
+    /// largest DNS message to send, in bytes, at most 65507; bigger replies are split into fragments
+    #[arg(long, default_value_t = 65535, value_parser = clap::value_parser!(u16).range(512..))]
+    max_size: u16,
+    /// TTL of the answers carrying replies, 0 discourages resolvers from caching them
+    #[arg(long, default_value_t = 0)]
+    answer_ttl: u32,
+    /// character-strings packed into each TXT record
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..))]
+    txt_strings: u8,
+    /// length of each character-string but the last, up to 255; the other end needs no
+    /// setting, strings are joined whatever their length
+    #[arg(long, default_value_t = TXT_L as u8, value_parser = clap::value_parser!(u8).range(1..))]
+    txt_chunk: u8,
+    /// random TXT records mixed in with those carrying each reply, marked by a leading '-'
+    /// no base64 starts with; clients skip them whatever their settings
+    #[arg(long, default_value_t = 0)]
+    decoy_records: u8,
+    /// pad smaller replies up to this many bytes with a decoy record, so short ones don't
+    /// stand out; at most `--max-size`
+    #[arg(long, default_value_t = 0)]
+    min_response_bytes: u16,
+    /// DEBUGGING ONLY, NOT FOR PRODUCTION: use this message id for every reply, easing capture
+    /// filters but breaking resolver correlation and caching, and reassembly of replies whose
+    /// fragments interleave
+    #[arg(long)]
+    fixed_id: Option<u16>,
+    /// DEBUGGING ONLY, NOT FOR PRODUCTION: send the records of each reply in random order, as
+    /// a reordering resolver would, to try `--answer-order indexed` out locally
+    #[arg(long, hide = true)]
+    shuffle_answers: bool,
+    /// how the receiving side puts answers back in order, both ends must agree
+    #[arg(long, value_enum, default_value_t = AnswerOrder::Ordered)]
+    answer_order: AnswerOrder,
+    /// what the client does with answers sharing an index, as some resolvers repeat records;
+    /// only `--answer-order indexed` can tell them
+    #[arg(long, value_enum, default_value_t = Duplicates::Drop)]
+    duplicate_answers: Duplicates,
+    /// message section the TXT records go in, both ends must agree
+    #[arg(long, value_enum, default_value_t = Section::Answer)]
+    section: Section,
+    /// reject replies that don't quite conform, with bytes after the last record or that
+    /// aren't a query response
+    #[arg(long, conflicts_with = "parse_lenient")]
+    parse_strict: bool,
+    /// take what records parse from replies a middlebox mangled, e.g. with more records
+    /// counted than there are; a reply cut short then comes out short
+    #[arg(long)]
+    parse_lenient: bool,
+    /// add a parity fragment per this many fragments of a split reply, letting the client
+    /// rebuild one lost fragment of each group; both ends must agree
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    fec_group: Option<u8>,
+    /// how replies from the destination are framed
+    #[arg(long, value_enum, default_value_t = DEFAULT_CODEC)]
+    codec: Codec,
+    /// also accept datagrams from the destination on the listener, for local end-to-end testing;
+    /// this can create loops
+    #[arg(long)]
+    allow_dst_source: bool,
+    /// start even when a destination is the listener itself
+    #[arg(long)]
+    force: bool,
+    /// log up to this many bytes of each new session's first datagram as hex, at debug level
+    #[arg(long, default_value_t = 0)]
+    log_first_packet: usize,
+    /// most new sessions accepted per second, protecting against scans from many sources
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    new_session_rate: Option<u32>,
+    /// payload bytes per second each session may relay in each direction, unlimited if unset;
+    /// a direction over it stops taking datagrams until it's back within, the queue or the
+    /// socket holding them meanwhile
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    session_rate: Option<u32>,
+    /// bytes a session may relay at once above `--session-rate`, defaults to one second's worth
+    #[arg(long)]
+    session_burst: Option<u32>,
+    /// on shutdown, save sessions to this file and take them over again on the next start,
+    /// keeping their relay ports if they are restarted within `timeout`, by the wall clock
+    /// as it spans restarts
+    #[arg(long)]
+    state_file: Option<String>,
+    /// tasks receiving on the listener at once, to drain bursts before the kernel drops them;
+    /// with more than one, a client's datagrams can reach dst out of the order it sent them,
+    /// as the tasks race to queue them, so only for protocols that put up with reordering
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    recv_workers: u16,
+    /// worker threads of the runtime, defaults to one per CPU
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    runtime_threads: Option<u16>,
+    /// run everything on the main thread, sparing the worker pool on a single CPU
+    #[arg(long, conflicts_with = "runtime_threads")]
+    single_thread: bool,
+    /// largest datagram taken from the listener, bigger ones are dropped; at most 65507,
+    /// what one UDP datagram can carry on to the other end. The id of `--session-id` doesn't
+    /// count, the server takes that much more
+    #[arg(long, default_value_t = 4096, value_parser = clap::value_parser!(u16).range(1..=65507))]
+    max_datagram: u16,
+    /// most bytes of TXT strings the client takes from one message, bigger ones are dropped
+    /// before being joined and decoded; defaults to what a message of `--max-size` holds, so
+    /// giving the client the server's `--max-size` tightens it
+    #[arg(long)]
+    max_txt_bytes: Option<usize>,
+    /// most bytes a session holds for replies still incomplete, their fragments and a slot
+    /// for each fragment expected; the oldest replies are dropped to stay within it
+    #[arg(long, default_value_t = 1 << 20)]
+    max_pending_bytes: usize,
+    /// tag each upstream datagram with a random per-session id, letting the server keep a
+    /// session whose source port a NAT changed midway; both ends must agree
+    #[arg(long)]
+    session_id: bool,
+    /// lead each datagram the server sends to dst with a PROXY protocol v2 header from the
+    /// tunnel's peer, the resolver or client whose queries the listener got, to the address
+    /// they reached it on; for backends telling peers apart, dst has to strip it and plain UDP
+    /// apps would take it as data. The applications behind a client stay hidden, the tunnel
+    /// doesn't carry their addresses
+    #[arg(long, conflicts_with = "client")]
+    proxy_protocol: bool,
+    /// DEBUGGING ONLY: copy every datagram the tunnel carries, decoded, to this UDP address,
+    /// or to this pcap file if it has a '/' in it; whatever the tunnel hides is in the clear there
+    #[arg(long)]
+    mirror: Option<String>,
+    /// serve "/healthz", "/ready", "/destinations" and "/metrics" over HTTP on this address,
+    /// or on this Unix socket if it has a '/' in it
+    #[arg(long)]
+    health_addr: Option<String>,
+    /// take admin commands, `list` and `kill <source>`, on this address or Unix socket
+    #[arg(long)]
+    control_addr: Option<String>,
+    /// user (name or uid) to switch to once listening, e.g. after binding port 53 as root
+    #[arg(long)]
+    user: Option<String>,
+    /// group (name or gid) to switch to once listening, defaults to the user's group
+    #[arg(long)]
+    group: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AnswerOrder {
+    /// rely on the order of the answers, costing nothing but breaking on paths that reorder them
+    Ordered,
+    /// lead every answer with its index, a few bytes per record that survive reordering resolvers
+    Indexed,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Duplicates {
+    /// keep the first answer of each index
+    Drop,
+    /// give up on the whole message, for paths where a repeat means tampering
+    Reject,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Section {
+    Answer,
+    /// for paths that cache or limit the answer section in the way
+    Additional,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// send TXT queries through a resolver and report what the path supports, to pick the
+    /// codec and --max-size before relaying
+    Probe {
+        resolver: String,
+        /// name whose TXT records are queried
+        name: String,
+        /// queries sent to measure round trips and answer order
+        #[arg(long, default_value_t = 5)]
+        count: usize,
+        /// seconds to wait for each answer
+        #[arg(long, default_value_t = 2)]
+        timeout: u64,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Codec {
+    /// replies are carried base64 encoded in DNS TXT answers
+    Txt,
+    /// no DNS framing at all, bytes are forwarded verbatim in both directions;
+    /// this is a plain UDP relay for debugging and does not tunnel anything
+    RawPassthrough,
+}
+
+/// builds without the `dns` feature are a plain relay
+#[cfg(feature = "dns")]
+const DEFAULT_CODEC: Codec = Codec::Txt;
+#[cfg(not(feature = "dns"))]
+const DEFAULT_CODEC: Codec = Codec::RawPassthrough;
+
+#[cfg(feature = "dns")]
 const BUF_SIZE: usize = 0x1000;
+/// largest UDP payload; relays take whole datagrams from dst, replies bigger than one
+/// message get fragmented rather than cut at BUF_SIZE
+const MAX_DATAGRAM: usize = 0xffff;
 const TXT_L: usize = 255;
 
-type Table = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Bytes>>>>;
+/// pause between lookups of a destination
+const RESOLVE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// socket errors in a row after which a relay replaces its socket
+const REBIND_AFTER: u32 = 3;
+
+/// how often dropped datagrams are reported
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+type Table = Mutex<HashMap<SessionKey, Session>>;
+
+/// length of the id leading upstream datagrams with `--session-id`
+const SESSION_ID_L: usize = 8;
+
+/// what sessions are told apart by
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum SessionKey {
+    /// the client's address, see `client_key`
+    Addr(SocketAddr),
+    /// the id the client puts in front of each datagram, with `--session-id` on the server
+    Id(u64),
+}
+
+struct Session {
+    tx: queue::Sender,
+    /// where replies go, the client's address as the listener last saw it
+    peer: watch::Sender<SocketAddr>,
+    /// address of the relay socket, once bound
+    local: Option<SocketAddr>,
+}
+
+/// table key for a client, one key whether or not a dual-stack listener sees it v4-mapped
+fn client_key(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// whether datagrams sent to `dst` would come back in on a listener bound to `listen`
+fn loops_back(listen: SocketAddr, dst: SocketAddr) -> bool {
+    let (listen, dst) = (client_key(listen), client_key(dst));
+    listen.port() == dst.port()
+        && (listen.ip() == dst.ip()
+            || listen.ip().is_unspecified()
+                && (dst.ip().is_loopback() || dst.ip().is_unspecified()))
+}
+
+/// complaint about each destination `loops_back` to `listen`
+fn loops(listen: SocketAddr, dsts: &Destinations) -> Vec<String> {
+    [Some(dsts.primary()), dsts.backup()]
+        .into_iter()
+        .flatten()
+        .filter(|dst| loops_back(listen, *dst))
+        .map(|dst| {
+            format!(
+                "destination {} is the listener on {}, datagrams would loop",
+                dst, listen
+            )
+        })
+        .collect()
+}
+
+/// what the main loop and every relay share
+struct Context {
+    config: Config,
+    dsts: Arc<Destinations>,
+    table: Table,
+    stats: Arc<Stats>,
+    /// limits `--new-session-rate`
+    new_sessions: std::sync::Mutex<Option<TokenBucket>>,
+    /// replies to send out of the listener
+    tx: Sender<(SocketAddr, Bytes)>,
+    /// address of the listener, for `--proxy-protocol`
+    listen: SocketAddr,
+    mirror: Option<mirror::Mirror>,
+}
+
+/// primary and backup destinations; the primary's health is tracked here, for all relays
+struct Destinations {
+    /// addresses can change with `--re-resolve`, relays read them on every use
+    primary_addr: std::sync::RwLock<SocketAddr>,
+    backup_addr: Option<std::sync::RwLock<SocketAddr>>,
+    failback: Option<Duration>,
+    /// when the primary was found unreachable
+    down_since: std::sync::Mutex<Option<Instant>>,
+    /// what `health` times are counted from
+    start: Instant,
+    primary_health: DstHealth,
+    backup_health: DstHealth,
+}
+
+/// how a destination has been doing, kept in atomics so relays update it without locking
+#[derive(Default)]
+struct DstHealth {
+    /// milliseconds from `Destinations::start` to the last reply, plus one; 0 if none yet
+    last_ok: AtomicU64,
+    /// failures since the last reply
+    failures: AtomicU64,
+}
+
+impl Destinations {
+    fn primary(&self) -> SocketAddr {
+        *self.primary_addr.read().unwrap()
+    }
+
+    fn backup(&self) -> Option<SocketAddr> {
+        self.backup_addr.as_ref().map(|b| *b.read().unwrap())
+    }
+
+    fn current(&self) -> SocketAddr {
+        match (self.backup(), *self.down_since.lock().unwrap()) {
+            (Some(backup), Some(since)) if !self.retried(since) => backup,
+            _ => self.primary(),
+        }
+    }
+
+    fn contains(&self, addr: SocketAddr) -> bool {
+        addr == self.primary() || Some(addr) == self.backup()
+    }
+
+    fn primary_failed(&self) {
+        if let Some(backup) = self.backup() {
+            let mut down_since = self.down_since.lock().unwrap();
+            if down_since.is_none_or(|since| self.retried(since)) {
+                warn!(
+                    "destination {} unreachable, failing over to {}",
+                    self.primary(),
+                    backup
+                );
+                *down_since = Some(Instant::now());
+            }
+        }
+    }
+
+    fn primary_ok(&self) {
+        if self.down_since.lock().unwrap().take().is_some() {
+            warn!("destination {} is back", self.primary());
+        }
+    }
+
+    /// the primary, or the backup if `backup`, was looked up again and found at `addr`
+    fn moved(&self, backup: bool, addr: SocketAddr) {
+        let slot = match (backup, &self.backup_addr) {
+            (false, _) => &self.primary_addr,
+            (true, Some(slot)) => slot,
+            (true, None) => return,
+        };
+        let old = std::mem::replace(&mut *slot.write().unwrap(), addr);
+        if old != addr {
+            warn!("destination {} moved to {}", old, addr);
+        }
+    }
+
+    /// whether a failover at `since` has already been retried
+    fn retried(&self, since: Instant) -> bool {
+        self.failback.is_some_and(|d| since.elapsed() >= d)
+    }
+
+    fn health(&self, addr: SocketAddr) -> Option<&DstHealth> {
+        if addr == self.primary() {
+            Some(&self.primary_health)
+        } else if Some(addr) == self.backup() {
+            Some(&self.backup_health)
+        } else {
+            None
+        }
+    }
+
+    /// `addr` sent a reply
+    fn answered(&self, addr: SocketAddr) {
+        if let Some(health) = self.health(addr) {
+            let ms = self.start.elapsed().as_millis() as u64 + 1;
+            health.last_ok.store(ms, Ordering::Relaxed);
+            health.failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// `addr` refused, couldn't be sent to or left datagrams unanswered
+    fn failed(&self, addr: SocketAddr) {
+        if let Some(health) = self.health(addr) {
+            health.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// one line per destination, for the health endpoint's "/destinations"
+    fn report(&self) -> String {
+        let down = self
+            .down_since
+            .lock()
+            .unwrap()
+            .is_some_and(|s| !self.retried(s));
+        [
+            Some(("primary", self.primary(), &self.primary_health, down)),
+            self.backup()
+                .map(|backup| ("backup", backup, &self.backup_health, false)),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|(role, addr, health, down)| {
+            let failures = health.failures.load(Ordering::Relaxed);
+            let last_ok = match health.last_ok.load(Ordering::Relaxed) {
+                0 => String::from("never answered"),
+                ms => {
+                    let at = self.start + Duration::from_millis(ms - 1);
+                    format!("last answered {}s ago", at.elapsed().as_secs())
+                }
+            };
+            format!(
+                "{} {} {}, {} failures in a row, {}\n",
+                addr,
+                role,
+                if down || failures > 0 { "down" } else { "up" },
+                failures,
+                last_ok
+            )
+        })
+        .collect()
+    }
+}
+
+/// counters shared by the main loop and the relays
+#[derive(Default)]
+struct Stats {
+    /// datagrams dropped because a queue was full
+    dropped: AtomicU64,
+    /// new sessions refused by `--new-session-rate`
+    rejected: AtomicU64,
+    /// datagrams over `--max-datagram` dropped by the listener
+    oversized: AtomicU64,
+    /// replies to the client without any TXT answer, e.g. NODATA from a resolver
+    empty: AtomicU64,
+    /// replies dropped by the client over `--max-txt-bytes` or `--max-pending-bytes`
+    capped: AtomicU64,
+    /// payload bytes, and bytes of what carried them between client and server,
+    /// upstream and downstream
+    payload: [AtomicU64; 2],
+    wire: [AtomicU64; 2],
+    /// sessions ended, by `Teardown`
+    ended: [AtomicU64; Teardown::ALL.len()],
+    /// inode of the listening socket, where the kernel can tell its drops
+    listener: std::sync::OnceLock<u64>,
+}
+
+/// index of each direction in `Stats::payload` and `Stats::wire`
+const UP: usize = 0;
+const DOWN: usize = 1;
+
+impl Stats {
+    fn queued<T>(&self, r: std::result::Result<(), TrySendError<T>>) {
+        if let Err(TrySendError::Full(_)) = r {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// `payload` bytes went `UP` or `DOWN` the tunnel as `wire` bytes
+    fn carried(&self, direction: usize, payload: usize, wire: usize) {
+        self.payload[direction].fetch_add(payload as u64, Ordering::Relaxed);
+        self.wire[direction].fetch_add(wire as u64, Ordering::Relaxed);
+    }
+
+    /// datagrams the kernel dropped on the listening socket, where it tells
+    fn kernel_dropped(&self) -> Option<u64> {
+        self.listener.get().copied().and_then(kernel_drops)
+    }
+
+    /// counters in the Prometheus text format, for the health endpoint's "/metrics"
+    fn metrics(&self) -> String {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut out = String::new();
+
+        for (name, counter) in [
+            ("dropped", &self.dropped),
+            ("rejected_sessions", &self.rejected),
+            ("oversized", &self.oversized),
+            ("empty_replies", &self.empty),
+            ("capped_replies", &self.capped),
+        ] {
+            out += &format!("udp2dns_{}_total {}\n", name, load(counter));
+        }
+        if let Some(n) = self.kernel_dropped() {
+            out += &format!("udp2dns_kernel_dropped_total {}\n", n);
+        }
+
+        let (payload, wire) = (
+            self.payload.each_ref().map(load),
+            self.wire.each_ref().map(load),
+        );
+        for (direction, d) in [("up", UP), ("down", DOWN)] {
+            out += &format!(
+                "udp2dns_payload_bytes_total{{direction=\"{}\"}} {}\n",
+                direction, payload[d]
+            );
+            out += &format!(
+                "udp2dns_wire_bytes_total{{direction=\"{}\"}} {}\n",
+                direction, wire[d]
+            );
+        }
+
+        for reason in Teardown::ALL {
+            out += &format!(
+                "udp2dns_sessions_ended_total{{reason=\"{}\"}} {}\n",
+                reason.name(),
+                load(&self.ended[reason as usize])
+            );
+        }
+
+        // bytes on the wire per payload byte, NaN until something went through
+        let ratio = |wire: u64, payload: u64| wire as f64 / payload as f64;
+        for (direction, ratio) in [
+            ("up", ratio(wire[UP], payload[UP])),
+            ("down", ratio(wire[DOWN], payload[DOWN])),
+            (
+                "all",
+                ratio(wire[UP] + wire[DOWN], payload[UP] + payload[DOWN]),
+            ),
+        ] {
+            out += &format!(
+                "udp2dns_overhead_ratio{{direction=\"{}\"}} {}\n",
+                direction, ratio
+            );
+        }
+        out
+    }
+}
+
+/// warns when queues overflow, so `bufsize` can be tuned, and sums up refused sessions,
+/// oversized datagrams, empty and capped replies instead of logging each of them
+async fn report_drops(ctx: Arc<Context>) {
+    let stats = &ctx.stats;
+    let mut interval = tokio::time::interval(DROP_REPORT_INTERVAL);
+
+    // what each counter counts, and what to do about it
+    let counters = [
+        (
+            &stats.dropped,
+            "datagrams dropped on full queues",
+            ", consider a larger --bufsize",
+        ),
+        (
+            &stats.rejected,
+            "new sessions refused",
+            ", over --new-session-rate",
+        ),
+        (
+            &stats.oversized,
+            "datagrams over --max-datagram dropped",
+            "",
+        ),
+        (&stats.empty, "replies without TXT answers ignored", ""),
+        (
+            &stats.capped,
+            "replies over --max-txt-bytes or --max-pending-bytes dropped",
+            "",
+        ),
+    ];
+    let (mut last, mut last_kernel) = (counters.map(|_| 0), None);
+
+    loop {
+        interval.tick().await;
+
+        for ((counter, what, hint), last) in counters.iter().zip(&mut last) {
+            let n = counter.load(Ordering::Relaxed);
+            if n > *last {
+                warn!(
+                    "{} {} in the last {}s{}",
+                    n - *last,
+                    what,
+                    DROP_REPORT_INTERVAL.as_secs(),
+                    hint
+                );
+            }
+            *last = n;
+        }
+
+        let kernel = stats.kernel_dropped();
+        if let (Some(kernel), Some(last_kernel)) = (kernel, last_kernel) {
+            if kernel > last_kernel {
+                warn!(
+                    "{} datagrams dropped by the kernel on the listener in the last {}s, consider more --recv-workers",
+                    kernel - last_kernel,
+                    DROP_REPORT_INTERVAL.as_secs()
+                );
+            }
+        }
+        last_kernel = kernel;
+    }
+}
+
+/// inode of a socket, to find it in /proc/net
+#[cfg(target_os = "linux")]
+fn socket_inode(usock: &UdpSocket) -> Option<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: fstat only writes to the stat buffer
+    unsafe {
+        let mut st = std::mem::zeroed::<libc::stat>();
+        (libc::fstat(usock.as_raw_fd(), &mut st) == 0).then_some(st.st_ino)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn socket_inode(_usock: &UdpSocket) -> Option<u64> {
+    None
+}
+
+/// datagrams the kernel dropped on the UDP socket `inode`, as listed in /proc/net/udp
+fn kernel_drops(inode: u64) -> Option<u64> {
+    ["/proc/net/udp", "/proc/net/udp6"].iter().find_map(|path| {
+        std::fs::read_to_string(path)
+            .ok()?
+            .lines()
+            .skip(1)
+            .find_map(|l| {
+                let fields: Vec<&str> = l.split_whitespace().collect();
+                if fields.get(9)?.parse::<u64>().ok()? == inode {
+                    fields.last()?.parse().ok()
+                } else {
+                    None
+                }
+            })
+    })
+}
+
+/// refilled at `rate` per second, holding at most `burst`
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens =
+            (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    fn take(&mut self, n: f64) -> bool {
+        self.refill();
+
+        if self.tokens >= n {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// takes `n` even when short, returning when that's made up for if it was
+    fn reserve(&mut self, n: f64) -> Option<Instant> {
+        self.refill();
+
+        self.tokens -= n;
+        (self.tokens < 0.0).then(|| self.last + Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let config = Config::parse();
 
+    let mut runtime = if config.single_thread {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    if let Some(threads) = config.runtime_threads {
+        runtime.worker_threads(threads.into());
+    }
+    let r = runtime.enable_all().build()?.block_on(run(config));
+
+    // for scripts to tell from failing
+    if let Err(err) = &r {
+        if err.get_ref().is_some_and(|err| err.is::<once::NoReply>()) {
+            eprintln!("{}", err);
+            std::process::exit(once::EXIT_TIMEOUT);
+        }
+    }
+    r
+}
+
+async fn run(config: Config) -> Result<()> {
     env_logger::builder().parse_filters(&config.loglevel).init();
 
-    let dst = config.dst.to_socket_addrs().unwrap().next().unwrap();
+    #[cfg(feature = "dns")]
+    if let Some(Command::Probe {
+        resolver,
+        name,
+        count,
+        timeout,
+    }) = &config.command
+    {
+        return probe::probe(
+            resolve(resolver, &config).await?,
+            name,
+            *count,
+            Duration::from_secs(*timeout),
+        )
+        .await;
+    }
+    #[cfg(not(feature = "dns"))]
+    if let Some(Command::Probe { .. }) = &config.command {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "probe needs a build with the dns feature",
+        ));
+    }
 
-    let usock = UdpSocket::bind(config.listen).await?;
+    #[cfg(feature = "dns")]
+    if config.encode {
+        return dump::encode(&config);
+    }
+    #[cfg(feature = "dns")]
+    if config.decode {
+        return dump::decode(&config);
+    }
+    #[cfg(not(feature = "dns"))]
+    if config.encode || config.decode {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "--encode and --decode need a build with the dns feature",
+        ));
+    }
+
+    if config.check {
+        return check::check(&config).await;
+    }
+
+    let problems = check::validate(&config);
+    if !problems.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, problems.join(", ")));
+    }
+    if config.once {
+        return once::once(&config).await;
+    }
+
+    // both are required without a subcommand
+    let (listen, dst) = (
+        config.listen.as_deref().unwrap(),
+        config.dst.as_deref().unwrap(),
+    );
+
+    let backup = match &config.backup_dst {
+        Some(backup) => Some(resolve(backup, &config).await?.into()),
+        None => None,
+    };
+    let dsts = Arc::new(Destinations {
+        primary_addr: resolve(dst, &config).await?.into(),
+        backup_addr: backup,
+        failback: config.failback_after.map(Duration::from_secs),
+        down_since: Default::default(),
+        start: Instant::now(),
+        primary_health: Default::default(),
+        backup_health: Default::default(),
+    });
+
+    let usock = match activated_socket()? {
+        Some(usock) => usock,
+        None => UdpSocket::bind(listen).await?,
+    };
 
     warn!("listening on {}", usock.local_addr()?);
 
-    let mut buf = [0_u8; BUF_SIZE];
+    for problem in loops(usock.local_addr()?, &dsts) {
+        if !config.force {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                problem + ", pass --force to start anyway",
+            ));
+        }
+        warn!("{}", problem);
+    }
+
+    let ready = health::Ready::default();
+    let stats = Arc::new(Stats::default());
+    if let Some(inode) = socket_inode(&usock) {
+        stats.listener.set(inode).unwrap_or_default();
+    }
 
-    let table: Table = Arc::new(Mutex::new(HashMap::new()));
+    if let Some(addr) = &config.health_addr {
+        let listener = endpoint::Endpoint::bind(addr).await?;
+        warn!("health endpoint on {}", listener);
 
+        tokio::spawn(health::serve(
+            listener,
+            ready.clone(),
+            dsts.clone(),
+            stats.clone(),
+        ));
+    }
+
+    // bound before dropping privileges, served once there's a context
+    let control = match &config.control_addr {
+        Some(addr) => {
+            let listener = endpoint::Endpoint::bind(addr).await?;
+            warn!("control endpoint on {}", listener);
+            Some(listener)
+        }
+        None => None,
+    };
+
+    if config.user.is_some() || config.group.is_some() {
+        drop_privileges(config.user.as_deref(), config.group.as_deref())?;
+    }
+
+    let mirror = match &config.mirror {
+        Some(to) => {
+            let mirror = mirror::Mirror::open(to, &config).await?;
+            warn!("mirroring the datagrams tunneled to {}, in the clear", to);
+            Some(mirror)
+        }
+        None => None,
+    };
+
+    let usock = Arc::new(usock);
     let (tx, mut rx) = mpsc::channel::<(SocketAddr, Bytes)>(config.bufsize);
 
+    let new_sessions = config
+        .new_session_rate
+        .map(|r| TokenBucket::new(r.into(), r.into()));
+
+    let ctx = Arc::new(Context {
+        config,
+        dsts,
+        table: Default::default(),
+        stats,
+        new_sessions: std::sync::Mutex::new(new_sessions),
+        tx,
+        listen: usock.local_addr()?,
+        mirror,
+    });
+    let config = &ctx.config;
+
+    tokio::spawn(report_drops(ctx.clone()));
+    if let Some(secs) = config.re_resolve {
+        tokio::spawn(re_resolve(ctx.clone(), Duration::from_secs(secs)));
+    }
+    if let Some(listener) = control {
+        tokio::spawn(control::serve(listener, ctx.clone()));
+    }
+
+    if let Some(path) = &config.state_file {
+        let mut table = ctx.table.lock().await;
+        for (key, src, local) in load_sessions(path, Duration::from_secs(config.timeout)) {
+            let (ttx, rx) = queue::channel(config.bufsize, config.overflow);
+            let (peer, peer_rx) = watch::channel(src);
+            table.insert(
+                key,
+                Session {
+                    tx: ttx,
+                    peer,
+                    local: None,
+                },
+            );
+
+            tokio::spawn(relay(ctx.clone(), rx, key, peer_rx, Some(local)));
+        }
+    }
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    let mut receivers = JoinSet::new();
+    for _ in 0..config.recv_workers {
+        receivers.spawn(receive(ctx.clone(), usock.clone()));
+    }
+
+    ready.store(true, Ordering::Relaxed);
+
     loop {
         select! {
-            r = usock.recv_from(&mut buf) => {
-                let (received,from) = r?;
-                let mut tablel = table.lock().await;
+            Some(r) = receivers.join_next() => {
+                // receivers only return on errors
+                return r.map_err(Error::other)?;
+            },
+            r = rx.recv() => {
+                let (to,buf) = r.unwrap();
 
-                if from == dst {
-                    info!("ignored connection from destination");
+                debug!("forwarding to {}",to);
+                if let Err(err) = usock.send_to(&buf,to).await {
+                    warn!("cannot send {} bytes to {}: {}", buf.len(), to, err);
                 }
-                else if let Some(relayer) = tablel.get(&from) {
-                    debug!("{} bytes received from {}", received, from);
-                    relayer.try_send(Bytes::copy_from_slice(&buf[..received])).ok();
-                } else {
-                    info!("new connection from {}", from);
-                    debug!("{} bytes received from {}", received, from);
+            },
+            _ = &mut shutdown => {
+                info!("shutting down");
+                if let Some(path) = &config.state_file {
+                    save_sessions(&ctx, path).await?;
+                }
+                return Ok(());
+            }
+        };
+    }
+}
+
+/// takes datagrams from the listener to their sessions, starting sessions as needed
+async fn receive(ctx: Arc<Context>, usock: Arc<UdpSocket>) -> Result<()> {
+    let (config, dsts, stats) = (&ctx.config, &ctx.dsts, &ctx.stats);
 
-                    let (ttx, rx) = mpsc::channel::<Bytes>(config.bufsize);
-                    tablel.insert(from, ttx);
+    // the id a client leads datagrams with doesn't count, it checked them before adding it
+    let max = match config.session_id && !config.client {
+        true => usize::from(config.max_datagram) + SESSION_ID_L,
+        false => config.max_datagram.into(),
+    };
+    // one byte more tells datagrams that are too big from ones that just fit
+    let mut buf = vec![0_u8; max + 1];
 
-                    tokio::spawn(relay(config.client,config.timeout,tx.clone(),rx,from,dst,table.clone()));
+    loop {
+        let (received, from) = usock.recv_from(&mut buf).await?;
+        if received > max {
+            debug!("dropped datagram over --max-datagram from {}", from);
+            stats.oversized.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
 
-                    tablel.get(&from).unwrap().try_send(Bytes::copy_from_slice(&buf[..received])).ok();
+        let (key, datagram) = if config.session_id && !config.client {
+            match buf[..received].split_first_chunk::<SESSION_ID_L>() {
+                Some((id, datagram)) => (SessionKey::Id(u64::from_be_bytes(*id)), datagram),
+                None => {
+                    debug!("dropped datagram without session id from {}", from);
+                    continue;
                 }
+            }
+        } else {
+            (SessionKey::Addr(client_key(from)), &buf[..received])
+        };
+        if !config.client {
+            stats.carried(UP, datagram.len(), received);
+        }
+        let mut tablel = ctx.table.lock().await;
+
+        if dsts.contains(client_key(from)) && !config.allow_dst_source {
+            info!("ignored connection from destination");
+        } else if let Some(session) = tablel.get(&key) {
+            debug!("{} bytes received from {}", received, from);
+            if session
+                .peer
+                .send_if_modified(|peer| std::mem::replace(peer, from) != from)
+            {
+                info!("session moved to {}", from);
+            }
+            stats.queued(session.tx.try_send(Bytes::copy_from_slice(datagram)));
+        } else if ctx
+            .new_sessions
+            .lock()
+            .unwrap()
+            .as_mut()
+            .is_some_and(|b| !b.take(1.0))
+        {
+            debug!("refused new connection from {}", from);
+            stats.rejected.fetch_add(1, Ordering::Relaxed);
+        } else {
+            info!("new connection from {}", from);
+            debug!("{} bytes received from {}", received, from);
+            if config.log_first_packet > 0 {
+                debug!(
+                    "first datagram from {}: {}",
+                    from,
+                    hex(&buf[..min(received, config.log_first_packet)])
+                );
+            }
+
+            let (ttx, rx) = queue::channel(config.bufsize, config.overflow);
+            let (peer, peer_rx) = watch::channel(from);
+            tablel.insert(
+                key,
+                Session {
+                    tx: ttx,
+                    peer,
+                    local: None,
+                },
+            );
+
+            tokio::spawn(relay(ctx.clone(), rx, key, peer_rx, None));
+
+            stats.queued(
+                tablel
+                    .get(&key)
+                    .unwrap()
+                    .tx
+                    .try_send(Bytes::copy_from_slice(datagram)),
+            );
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = term.recv() => {},
             },
-            r = rx.recv() => {
-                let (to,buf) = r.unwrap();
+            Err(_) => tokio::signal::ctrl_c().await.unwrap_or_default(),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap_or_default();
+}
 
-                debug!("forwarding to {}",to);
-                usock.send_to(&buf,to).await?;
+/// writes each session as its source and relay socket address, and its id if it has one,
+/// for the next start to take over
+async fn save_sessions(ctx: &Context, path: &str) -> Result<()> {
+    let table = ctx.table.lock().await;
+    let sessions: Vec<String> = table
+        .iter()
+        .filter_map(|(key, session)| {
+            let (peer, local) = (*session.peer.borrow(), session.local?);
+            Some(match key {
+                SessionKey::Addr(_) => format!("{} {}\n", peer, local),
+                SessionKey::Id(id) => format!("{} {} {}\n", peer, local, id),
+            })
+        })
+        .collect();
+
+    tokio::fs::write(path, sessions.concat()).await?;
+    warn!("saved {} sessions to {}", sessions.len(), path);
+    Ok(())
+}
+
+/// sessions saved by `save_sessions`, unless older than `max_age`
+fn load_sessions(path: &str, max_age: Duration) -> Vec<(SessionKey, SocketAddr, SocketAddr)> {
+    let read = || -> Result<_> {
+        // dated in the future, the clock went back since and the age is unknown
+        let age = std::fs::metadata(path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or(Duration::MAX);
+        let sessions = std::fs::read_to_string(path)?;
+        std::fs::remove_file(path)?;
+        Ok((age, sessions))
+    };
+
+    let (age, sessions) = match read() {
+        Ok(r) => r,
+        Err(err) if err.kind() == ErrorKind::NotFound => return vec![],
+        Err(err) => {
+            warn!("cannot restore sessions from {}: {}", path, err);
+            return vec![];
+        }
+    };
+    if age > max_age {
+        info!("sessions in {} are too old to restore", path);
+        return vec![];
+    }
+
+    let sessions: Vec<_> = sessions
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split(' ');
+            let src = fields.next()?.parse().ok()?;
+            let local = fields.next()?.parse().ok()?;
+            let key = match fields.next() {
+                Some(id) => SessionKey::Id(id.parse().ok()?),
+                None => SessionKey::Addr(client_key(src)),
+            };
+            Some((key, src, local))
+        })
+        .collect();
+    warn!("restoring {} sessions from {}", sessions.len(), path);
+    sessions
+}
+
+/// looks the destinations up every `every`, relays pick new addresses up on their next send
+async fn re_resolve(ctx: Arc<Context>, every: Duration) {
+    let config = &ctx.config;
+    let mut interval = tokio::time::interval_at(Instant::now() + every, every);
+
+    loop {
+        interval.tick().await;
+
+        let names = [
+            (false, config.dst.as_deref()),
+            (true, config.backup_dst.as_deref()),
+        ];
+        for (backup, name) in names {
+            let Some(name) = name else { continue };
+            match resolve(name, config).await {
+                Ok(addr) => ctx.dsts.moved(backup, addr),
+                Err(err) => warn!(
+                    "{}, keeping {}",
+                    err,
+                    if backup { "the backup" } else { "dst" }
+                ),
             }
+        }
+    }
+}
+
+/// resolves `addr`, retrying lookups that fail or hang
+async fn resolve(addr: &str, config: &Config) -> Result<SocketAddr> {
+    let timeout = Duration::from_secs(config.resolve_timeout);
+
+    let mut attempt = 0;
+    loop {
+        let err = match tokio::time::timeout(timeout, lookup_host(addr)).await {
+            Ok(Ok(mut addrs)) => match addrs.next() {
+                Some(addr) => return Ok(addr),
+                None => Error::new(ErrorKind::NotFound, "no address"),
+            },
+            Ok(Err(err)) => err,
+            Err(_) => Error::new(ErrorKind::TimedOut, "lookup timed out"),
         };
+
+        if attempt >= config.resolve_retries {
+            return Err(Error::new(
+                err.kind(),
+                format!("cannot resolve {}: {}", addr, err),
+            ));
+        }
+        warn!("cannot resolve {}: {}, retrying", addr, err);
+        attempt += 1;
+        tokio::time::sleep(RESOLVE_RETRY_DELAY).await;
     }
 }
 
+/// the listening socket passed by systemd socket activation, if any
+#[cfg(unix)]
+fn activated_socket() -> Result<Option<UdpSocket>> {
+    use std::os::unix::io::FromRawFd;
+
+    const LISTEN_FDS_START: i32 = 3;
+
+    let var = |name| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok());
+    let fds = match (var("LISTEN_PID"), var("LISTEN_FDS")) {
+        (Some(pid), Some(fds)) if pid == std::process::id() && fds > 0 => fds,
+        _ => return Ok(None),
+    };
+    if fds > 1 {
+        warn!("{} sockets passed, using the first one", fds);
+    }
+
+    // SAFETY: systemd hands the descriptor over to us, nothing else owns it
+    let usock = unsafe { std::net::UdpSocket::from_raw_fd(LISTEN_FDS_START) };
+    usock.set_nonblocking(true)?;
+
+    info!("using socket passed by systemd");
+    UdpSocket::from_std(usock).map(Some)
+}
+
+#[cfg(not(unix))]
+fn activated_socket() -> Result<Option<UdpSocket>> {
+    Ok(None)
+}
+
+/// uid and gid for `--user` and `--group`, the gid defaulting to the user's group; a
+/// user without one, a uid missing from the passwd database, needs `--group`
+#[cfg(unix)]
+fn lookup_ids(
+    user: Option<&str>,
+    group: Option<&str>,
+) -> Result<(Option<libc::uid_t>, Option<libc::gid_t>)> {
+    use std::ffi::CString;
+
+    let not_found = |what, name| Error::new(ErrorKind::NotFound, format!("no {} {}", what, name));
+    let c_name =
+        |name: &str| CString::new(name).map_err(|err| Error::new(ErrorKind::InvalidInput, err));
+
+    let user = match user {
+        Some(name) => Some(match name.parse::<libc::uid_t>() {
+            Ok(uid) => {
+                // SAFETY: as below
+                let pw = unsafe { libc::getpwuid(uid) };
+                // SAFETY: checked for null first
+                (uid, (!pw.is_null()).then(|| unsafe { (*pw).pw_gid }))
+            }
+            Err(_) => {
+                // SAFETY: called before any other thread looks users up
+                let pw = unsafe { libc::getpwnam(c_name(name)?.as_ptr()) };
+                if pw.is_null() {
+                    return Err(not_found("user", name));
+                }
+                // SAFETY: checked for null above
+                unsafe { ((*pw).pw_uid, Some((*pw).pw_gid)) }
+            }
+        }),
+        None => None,
+    };
+
+    let gid = match group {
+        Some(name) => Some(match name.parse::<libc::gid_t>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                // SAFETY: as above
+                let gr = unsafe { libc::getgrnam(c_name(name)?.as_ptr()) };
+                if gr.is_null() {
+                    return Err(not_found("group", name));
+                }
+                // SAFETY: checked for null above
+                unsafe { (*gr).gr_gid }
+            }
+        }),
+        None => match user {
+            Some((uid, None)) => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("uid {} has no group to switch to, pass --group", uid),
+                ))
+            }
+            Some((_, gid)) => gid,
+            None => None,
+        },
+    };
+
+    Ok((user.map(|(uid, _)| uid), gid))
+}
+
+/// whether switching to `uid` and `gid` needs root, as it changes the effective ids; running
+/// as them already, there's nothing to do
+#[cfg(unix)]
+fn needs_root(uid: Option<libc::uid_t>, gid: Option<libc::gid_t>) -> bool {
+    // SAFETY: plain syscalls
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    euid != 0 && (uid.is_some_and(|uid| uid != euid) || gid.is_some_and(|gid| gid != egid))
+}
+
+#[cfg(unix)]
+fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let (uid, gid) = lookup_ids(user, group)?;
+    if needs_root(uid, gid) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "switching user or group needs root",
+        ));
+    }
+
+    // SAFETY: plain syscall
+    let root = unsafe { libc::geteuid() } == 0;
+
+    // root's supplementary groups are dropped whatever is switched to, anyone else has none
+    // to drop, nor ids to change
+    if root {
+        // SAFETY: plain syscalls, the groups go first while we may still change them
+        unsafe {
+            if libc::setgroups(0, std::ptr::null()) != 0 {
+                return Err(Error::last_os_error());
+            }
+            if let Some(gid) = gid {
+                if libc::setgid(gid) != 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+            if let Some(uid) = uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+        }
+    }
+
+    // SAFETY: plain syscalls
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    if uid != 0 && gid == 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("uid {} would keep gid 0, pass --group", uid),
+        ));
+    }
+    warn!("running as uid {}, gid {}", uid, gid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_user: Option<&str>, _group: Option<&str>) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "--user and --group are only supported on unix",
+    ))
+}
+
+/// why a session ended
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Teardown {
+    /// both directions idle
+    Idle,
+    /// dst never answered within `--first-response`
+    NoResponse,
+    /// taken out of the table on the control endpoint
+    Killed,
+    /// its relay socket failed for good
+    Error,
+}
+
+impl Teardown {
+    const ALL: [Teardown; 4] = [
+        Teardown::Idle,
+        Teardown::NoResponse,
+        Teardown::Killed,
+        Teardown::Error,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Teardown::Idle => "idle",
+            Teardown::NoResponse => "no_response",
+            Teardown::Killed => "killed",
+            Teardown::Error => "error",
+        }
+    }
+}
+
+/// relays a session until it ends, then takes it out of the table and tells why
 async fn relay(
-    is_client: bool,
-    timeout: u64,
+    ctx: Arc<Context>,
+    mut rx: queue::Receiver,
+    key: SessionKey,
+    peer: watch::Receiver<SocketAddr>,
+    bind: Option<SocketAddr>,
+) {
+    let src = *peer.borrow();
+    let reason = match relay_session(&ctx, &mut rx, key, peer, bind).await {
+        Ok(reason) => reason,
+        Err(err) => {
+            warn!("relay for {} failed: {}", src, err);
+            Teardown::Error
+        }
+    };
 
-    tx: Sender<(SocketAddr, Bytes)>,
-    mut rx: Receiver<Bytes>,
-    src: SocketAddr,
-    dst: SocketAddr,
-    table: Table,
-) -> Result<()> {
-    let mut buf = [0_u8; BUF_SIZE];
+    // a killed session is out of the table already
+    if reason != Teardown::Killed {
+        end_session(&ctx, key, &mut rx).await;
+    }
+    info!("session of {} ended: {}", src, reason.name());
+    ctx.stats.ended[reason as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// relays a session, on the relay socket address `bind` when taking over a restored one
+///
+/// the relay socket belongs to the session until it ends and is never shared, so the destination
+/// and any NAT on the way see one stable source port per session; NATs that see the port change
+/// mid-session would map it to a new binding and drop replies sent to the old one. Only a socket
+/// failing `REBIND_AFTER` times in a row is replaced, or one of the other family than the
+/// destination it has to reach after failing over. It is connected to the destination in use,
+/// as the kernel only reports the ICMP errors telling a destination unreachable on connected
+/// sockets, and reconnected when failing over or the destination moves; an error a datagram
+/// brought is picked up before sending the next one
+async fn relay_session(
+    ctx: &Context,
+    rx: &mut queue::Receiver,
+    key: SessionKey,
+    peer: watch::Receiver<SocketAddr>,
+    bind: Option<SocketAddr>,
+) -> Result<Teardown> {
+    let (config, dsts, stats) = (&ctx.config, &ctx.dsts, &ctx.stats);
+    let src = *peer.borrow();
+
+    // leads what the client sends upstream with `--session-id`
+    let tag = (config.client && config.session_id).then(|| rand::random::<u64>().to_be_bytes());
+
+    let mut buf = vec![0_u8; MAX_DATAGRAM];
+
+    let mut usock = match bind {
+        Some(local) => match UdpSocket::bind(local).await {
+            Ok(usock) => usock,
+            Err(err) => {
+                warn!("cannot rebind {} for {}: {}", local, src, err);
+                UdpSocket::bind(any_addr(dsts.current())).await?
+            }
+        },
+        None => UdpSocket::bind(any_addr(dsts.current())).await?,
+    };
+    socket_options(&usock, config)?;
+    let mut local = usock.local_addr()?;
+
+    info!("relaying {} from {}", src, local);
+
+    if let Some(session) = ctx.table.lock().await.get_mut(&key) {
+        session.local = Some(local);
+    }
 
-    let usock = UdpSocket::bind("0.0.0.0:0").await?;
+    // socket errors in a row, other than refusals
+    let mut errors = 0;
+    // where the relay socket is connected to, once it sent anything
+    let mut connected: Option<SocketAddr> = None;
+    // the destination refused a datagram, it is unreachable
+    let refused = |dst: SocketAddr| {
+        dsts.failed(dst);
+        if dst == dsts.primary() {
+            dsts.primary_failed();
+        }
+    };
+
+    let timeout = config.timeout;
+    let up_idle = Duration::from_secs(config.up_idle.unwrap_or(timeout));
+    let down_idle = Duration::from_secs(config.down_idle.unwrap_or(timeout));
+
+    // last activity, and whether the direction has been reported idle since
+    let (mut up, mut up_quiet) = (Instant::now(), false);
+    let (mut down, mut down_quiet) = (Instant::now(), false);
 
-    let mut timer = Instant::now();
+    #[cfg(feature = "dns")]
+    let mut reassembly = dns::Reassembly::new(
+        Duration::from_secs(timeout),
+        config.fec_group,
+        config.max_pending_bytes,
+    );
+
+    // oldest upstream datagram the primary hasn't answered yet
+    let mut unanswered: Option<Instant> = None;
+    let failover = Duration::from_secs(config.failover_after);
+
+    // set by the first forwarded datagram, cleared for good by the first reply
+    let mut first_deadline: Option<Instant> = None;
+    let mut answered = false;
+
+    let bucket = || {
+        config
+            .session_rate
+            .map(|rate| TokenBucket::new(rate.into(), config.session_burst.unwrap_or(rate).into()))
+    };
+    let (mut up_bucket, mut down_bucket) = (bucket(), bucket());
+    // when each direction is back within `--session-rate`, if it went over
+    let (mut up_ready, mut down_ready): (Option<Instant>, Option<Instant>) = (None, None);
+
+    // `--proxy-protocol` header, and the peer it was made for
+    let mut proxy_header: Option<(SocketAddr, Vec<u8>)> = None;
 
     loop {
+        let wake = [(up + up_idle, up_quiet), (down + down_idle, down_quiet)]
+            .into_iter()
+            .filter_map(|(t, quiet)| (!quiet).then_some(t))
+            .min()
+            .unwrap();
+
         select! {
-            r = tokio::time::timeout_at(
-                timer + Duration::from_secs(timeout),
-                usock.recv_from(&mut buf),
-            )=>{
-                let (received, from) = match r{
-                    Ok(r) => r?,
-                    Err(_) => {
-                        info!("timeout, stopping relay for {}", src);
-                        let mut tablel = table.lock().await;
-                        tablel.remove(&src);
-                        rx.close();
-                        return Ok(());
+            _ = tokio::time::sleep_until(wake) => {
+                let now = Instant::now();
+                if !up_quiet && now >= up + up_idle {
+                    info!("upstream of {} idle", src);
+                    up_quiet = true;
+                }
+                if !down_quiet && now >= down + down_idle {
+                    info!("downstream of {} idle", src);
+                    down_quiet = true;
+                }
+
+                if up_quiet && down_quiet {
+                    return Ok(Teardown::Idle);
+                }
+            },
+            _ = tokio::time::sleep_until(unanswered.unwrap_or(up) + failover), if unanswered.is_some() => {
+                unanswered = None;
+                dsts.failed(dsts.primary());
+                dsts.primary_failed();
+            },
+            _ = tokio::time::sleep_until(first_deadline.unwrap_or(up)), if first_deadline.is_some() => {
+                dsts.failed(dsts.current());
+                return Ok(Teardown::NoResponse);
+            },
+            _ = tokio::time::sleep_until(up_ready.unwrap_or(up)), if up_ready.is_some() => {
+                up_ready = None;
+            },
+            _ = tokio::time::sleep_until(down_ready.unwrap_or(down)), if down_ready.is_some() => {
+                down_ready = None;
+            },
+            r = usock.recv_from(&mut buf), if down_ready.is_none() => {
+                let (received, from) = match r {
+                    Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                        let dst = connected.unwrap_or_else(|| dsts.current());
+                        debug!("{} refused a datagram of {}: {}", dst, src, err);
+                        refused(dst);
+                        continue;
+                    }
+                    Err(err) => {
+                        debug!("cannot receive for {} on {}: {}", src, local, err);
+                        errors += 1;
+                        if errors >= REBIND_AFTER {
+                            let old = local;
+                            (usock, local) = rebind(ctx, key, dsts.current()).await?;
+                            warn!("relay socket {} keeps failing, moved to {}", old, local);
+                            (errors, connected) = (0, None);
+                        }
+                        continue;
+                    }
+                    Ok(r) => {
+                        errors = 0;
+                        r
                     }
                 };
 
-                if from == dst {
+                if from == dsts.primary() {
+                    unanswered = None;
+                    dsts.primary_ok();
+                }
+
+                if dsts.contains(from) {
                     debug!("{} bytes received from {}", received, from);
-                    if let Some(msg) = if is_client {
-                        dns_reply_decode(&buf[..received])
-                    } else {
-                        Some(dns_reply_encode(&buf[..received]))
-                    } {
-                        tx.try_send((src,msg)).ok();
-
-                        timer = Instant::now();
+                    dsts.answered(from);
+                    (first_deadline, answered) = (None, true);
+                    let msgs = match config.codec {
+                        Codec::RawPassthrough => vec![Bytes::copy_from_slice(&buf[..received])],
+                        #[cfg(feature = "dns")]
+                        Codec::Txt if config.client => dns::dns_reply_decode(&buf[..received], &mut reassembly, config, stats).into_iter().collect(),
+                        #[cfg(feature = "dns")]
+                        Codec::Txt => dns::dns_reply_encode(&buf[..received], config),
+                        // refused at startup
+                        #[cfg(not(feature = "dns"))]
+                        Codec::Txt => unreachable!(),
+                    };
+                    if !msgs.is_empty() {
+                        let payload = if config.client { msgs.iter().map(Bytes::len).sum() } else { received };
+                        let wire = match (config.client, config.codec) {
+                            (false, _) => msgs.iter().map(Bytes::len).sum(),
+                            // counted by dns_reply_decode, fragments completing nothing too
+                            (true, Codec::Txt) => 0,
+                            (true, Codec::RawPassthrough) => received,
+                        };
+                        stats.carried(DOWN, payload, wire);
+
+                        if let Some(mirror) = &ctx.mirror {
+                            let to = *peer.borrow();
+                            if config.client {
+                                for msg in &msgs {
+                                    mirror.copy(from, to, msg).await;
+                                }
+                            } else {
+                                mirror.copy(from, to, &buf[..received]).await;
+                            }
+                        }
+
+                        if let Some(bucket) = &mut down_bucket {
+                            down_ready = bucket.reserve(payload as f64);
+                        }
+                        let to = *peer.borrow();
+                        if let [msg] = &msgs[..] {
+                            stats.queued(ctx.tx.try_send((to, msg.clone())));
+                        } else {
+                            // losing one fragment loses the whole reply, and a reply can be
+                            // split into more than the listener's queue holds: wait for room
+                            for msg in msgs {
+                                if ctx.tx.send((to, msg)).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+
+                        (down, down_quiet) = (Instant::now(), false);
                     }
                 };
             },
-            r = rx.recv()=>{
+            r = rx.recv(), if up_ready.is_none() => {
+                // the session was taken out of the table, by the control endpoint
+                let Some(r) = r else {
+                    return Ok(Teardown::Killed);
+                };
+                if let Some(bucket) = &mut up_bucket {
+                    up_ready = bucket.reserve(r.len() as f64);
+                }
+
+                // an ICMP error doesn't wake a receive, it waits on the socket for this
+                if let (Some(dst), Ok(Some(err))) = (connected, usock.take_error()) {
+                    match err.kind() {
+                        ErrorKind::ConnectionRefused => {
+                            debug!("{} refused a datagram of {}: {}", dst, src, err);
+                            refused(dst);
+                        }
+                        _ => debug!("relay socket {} of {} failed: {}", local, src, err),
+                    }
+                }
+
+                let dst = dsts.current();
+                if let Some(mirror) = &ctx.mirror {
+                    let from = *peer.borrow();
+                    mirror.copy(from, dst, &r).await;
+                }
+
+                let payload = r.len();
+                let r = match &tag {
+                    Some(tag) => Bytes::from([&tag[..], &r].concat()),
+                    None if config.proxy_protocol => {
+                        let from = *peer.borrow();
+                        if proxy_header.as_ref().is_none_or(|(to, _)| *to != from) {
+                            proxy_header = Some((from, proxy::header(from, proxy::reached(from, ctx.listen))));
+                        }
+                        let (_, header) = proxy_header.as_ref().unwrap();
+                        Bytes::from([&header[..], &r].concat())
+                    },
+                    None => r,
+                };
+                if config.client {
+                    stats.carried(UP, payload, r.len());
+                }
+
+                // failed over to, or back from, a destination of the other family
+                if dst.is_ipv4() != local.is_ipv4() {
+                    (usock, local) = rebind(ctx, key, dst).await?;
+                    info!("relaying {} from {} to reach {}", src, local, dst);
+                    (errors, connected) = (0, None);
+                }
+
                 debug!("forwarding to {}",dst);
-                usock.send_to(&r.unwrap(),dst).await?;
+                let sent = async {
+                    if connected != Some(dst) {
+                        usock.connect(dst).await?;
+                        connected = Some(dst);
+                    }
+                    usock.send(&r).await
+                };
+                match sent.await {
+                    Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                        debug!("{} refused a datagram of {}: {}", dst, src, err);
+                        refused(dst);
+                        continue;
+                    }
+                    Err(err) => {
+                        debug!("cannot send to {} for {} on {}: {}", dst, src, local, err);
+                        dsts.failed(dst);
+                        errors += 1;
+                        if errors >= REBIND_AFTER {
+                            let old = local;
+                            (usock, local) = rebind(ctx, key, dst).await?;
+                            warn!("relay socket {} keeps failing, moved to {}", old, local);
+                            (errors, connected) = (0, None);
+                        }
+                        continue;
+                    }
+                    Ok(_) => {}
+                }
+                errors = 0;
 
-                timer = Instant::now();
+                if dst == dsts.primary() && dsts.backup_addr.is_some() {
+                    unanswered.get_or_insert_with(Instant::now);
+                }
+                if let (Some(secs), false) = (config.first_response, answered) {
+                    first_deadline.get_or_insert_with(|| Instant::now() + Duration::from_secs(secs));
+                }
+
+                (up, up_quiet) = (Instant::now(), false);
             }
 
         };
     }
 }
 
-fn dns_reply_encode(buf: &[u8]) -> Bytes {
-    let s = base64::encode(buf);
+/// takes the session `key` out of the table, unless a newer session took its place after the
+/// control endpoint killed it
+async fn end_session(ctx: &Context, key: SessionKey, rx: &mut queue::Receiver) {
+    let mut table = ctx.table.lock().await;
+    rx.close();
+    if table
+        .get(&key)
+        .is_some_and(|session| session.tx.is_closed())
+    {
+        table.remove(&key);
+    }
+}
+
+/// where relay sockets bind, any address of the destination's family
+fn any_addr(dst: SocketAddr) -> SocketAddr {
+    match dst {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// sets the options asked for on a relay socket, failing only with `--strict-sockopts`
+fn socket_options(usock: &UdpSocket, config: &Config) -> Result<()> {
+    if config.flow_label {
+        if let Err(err) = flow_label(usock) {
+            if config.strict_sockopts {
+                return Err(err);
+            }
+            warn!("{}", err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn flow_label(usock: &UdpSocket) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
 
-    let mut msg = Message::new();
-    msg.set_id(rand::random())
-        .add_answers((0..s.len()).step_by(TXT_L).map(|i| {
-            let mut r = Record::new();
-            r.set_record_type(RecordType::TXT)
-                .set_data(Some(RData::TXT(TXT::new(vec![String::from(
-                    &s[i..min(i + TXT_L, s.len())],
-                )]))));
-            r
-        }));
+    // not in libc yet, see ipv6(7)
+    const IPV6_AUTOFLOWLABEL: libc::c_int = 70;
 
-    Bytes::from(msg.to_vec().unwrap())
+    let local = usock.local_addr()?;
+    if !local.is_ipv6() {
+        return Ok(());
+    }
+
+    let on: libc::c_int = 1;
+    // SAFETY: setsockopt only reads `on`
+    let r = unsafe {
+        libc::setsockopt(
+            usock.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            IPV6_AUTOFLOWLABEL,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if r != 0 {
+        let err = Error::last_os_error();
+        return Err(Error::new(
+            err.kind(),
+            format!("cannot set IPV6_AUTOFLOWLABEL on {}: {}", local, err),
+        ));
+    }
+    Ok(())
 }
 
-fn dns_reply_decode(buf: &[u8]) -> Option<Bytes> {
-    match Message::from_vec(&buf) {
-        Ok(msg) => {
-            let mut s = String::new();
+#[cfg(not(target_os = "linux"))]
+fn flow_label(_usock: &UdpSocket) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "cannot set IPV6_AUTOFLOWLABEL, flow labels are Linux only",
+    ))
+}
+
+/// a fresh relay socket for the session `key`, of the family that reaches `dst`
+async fn rebind(
+    ctx: &Context,
+    key: SessionKey,
+    dst: SocketAddr,
+) -> Result<(UdpSocket, SocketAddr)> {
+    let usock = UdpSocket::bind(any_addr(dst)).await?;
+    socket_options(&usock, &ctx.config)?;
+    let local = usock.local_addr()?;
+
+    if let Some(session) = ctx.table.lock().await.get_mut(&key) {
+        session.local = Some(local);
+    }
+    Ok((usock, local))
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// helpers shared by the tests of every module
+#[cfg(test)]
+mod testing {
+    use super::{Config, MAX_DATAGRAM};
+    use clap::Parser;
+    use std::net::SocketAddr;
+    use tokio::net::UdpSocket;
+
+    /// `args` parsed after a listen address and dst
+    pub fn try_config(args: &[&str]) -> Result<Config, clap::Error> {
+        Config::try_parse_from(["udp2dns", "127.0.0.1:0", "127.0.0.1:1"].iter().chain(args))
+    }
+
+    pub fn config(args: &[&str]) -> Config {
+        try_config(args).unwrap()
+    }
+
+    /// `len` bytes of a pattern that doesn't repeat within a byte's range
+    pub fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// a destination answering each datagram with `reply` to it
+    pub async fn dst(reply: impl Fn(&[u8]) -> Vec<u8> + Send + 'static) -> SocketAddr {
+        dst_on("127.0.0.1:0", reply).await
+    }
+
+    pub async fn dst_on(
+        addr: &str,
+        reply: impl Fn(&[u8]) -> Vec<u8> + Send + 'static,
+    ) -> SocketAddr {
+        let usock = UdpSocket::bind(addr).await.unwrap();
+        let addr = usock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0_u8; MAX_DATAGRAM];
+            loop {
+                let (received, from) = usock.recv_from(&mut buf).await.unwrap();
+                usock.send_to(&reply(&buf[..received]), from).await.unwrap();
+            }
+        });
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{config, dst, dst_on, payload, try_config};
+
+    fn destinations(primary: SocketAddr, backup: Option<SocketAddr>) -> Destinations {
+        Destinations {
+            primary_addr: primary.into(),
+            backup_addr: backup.map(Into::into),
+            failback: None,
+            down_since: Default::default(),
+            start: Instant::now(),
+            primary_health: Default::default(),
+            backup_health: Default::default(),
+        }
+    }
+
+    /// a client or server with `args` relaying to `dst`, served the way `run` does on a
+    /// listener of its own
+    async fn serve(args: &[&str], dst: SocketAddr) -> Arc<Context> {
+        serve_dsts(args, destinations(dst, None)).await
+    }
+
+    async fn serve_dsts(args: &[&str], dsts: Destinations) -> Arc<Context> {
+        let usock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = config(args);
+        let (tx, mut rx) = mpsc::channel::<(SocketAddr, Bytes)>(config.bufsize);
+        let ctx = Arc::new(Context {
+            config,
+            dsts: Arc::new(dsts),
+            table: Default::default(),
+            stats: Default::default(),
+            new_sessions: std::sync::Mutex::new(None),
+            tx,
+            listen: usock.local_addr().unwrap(),
+            mirror: None,
+        });
+
+        tokio::spawn(receive(ctx.clone(), usock.clone()));
+        tokio::spawn(async move {
+            while let Some((to, buf)) = rx.recv().await {
+                usock.send_to(&buf, to).await.unwrap();
+            }
+        });
+        ctx
+    }
+
+    /// the next datagram on `usock`, failing the test if none comes in time
+    async fn recv(usock: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = vec![0_u8; MAX_DATAGRAM];
+        let (received, from) =
+            tokio::time::timeout(Duration::from_secs(5), usock.recv_from(&mut buf))
+                .await
+                .expect("no datagram")
+                .unwrap();
+        buf.truncate(received);
+        (buf, from)
+    }
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn replies_of_more_fragments_than_bufsize_get_through() {
+        // as many KB as the request's first byte says
+        let dst = dst(|req| payload(usize::from(req[0]) * 1000)).await;
+        let server = serve(&["--max-size", "512"], dst).await;
+        let client = serve(&["-c"], server.listen).await;
+        let app = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        for kb in [9, 18, 40] {
+            let fragments = dns::dns_reply_encode(&payload(kb * 1000), &server.config).len();
+            assert!(fragments > server.config.bufsize, "{} fragments", fragments);
+
+            app.send_to(&[kb as u8], client.listen).await.unwrap();
+            let (reply, from) = recv(&app).await;
+            assert_eq!(from, client.listen);
+            assert_eq!(reply, payload(kb * 1000));
+        }
+        for ctx in [&server, &client] {
+            assert_eq!(ctx.stats.dropped.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_datagrams_get_through_both_ways() {
+        // answers an empty datagram, and the one byte ones with an empty datagram
+        let dst = dst(|req| match req {
+            [] => b"empty".to_vec(),
+            _ => vec![],
+        })
+        .await;
+        let codecs = ["txt", "raw-passthrough"]
+            .into_iter()
+            .filter(|codec| cfg!(feature = "dns") || *codec != "txt");
+        for codec in codecs {
+            let server = serve(&["--codec", codec], dst).await;
+            let client = serve(&["-c", "--codec", codec], server.listen).await;
+            let app = UdpSocket::bind("127.0.0.1:0").await.unwrap();
 
-            msg.answers().iter().for_each(|rec| {
-                s += &rec.data().unwrap().as_txt().unwrap().to_string();
-            });
-            match base64::decode(s) {
-                Ok(b) => return Some(Bytes::from(b)),
-                Err(err) => {
-                    warn!("{}", err);
+            app.send_to(&[], client.listen).await.unwrap();
+            assert_eq!(
+                recv(&app).await,
+                (b"empty".to_vec(), client.listen),
+                "{}",
+                codec
+            );
+            app.send_to(b"x", client.listen).await.unwrap();
+            assert_eq!(recv(&app).await, (vec![], client.listen), "{}", codec);
+        }
+    }
+
+    #[tokio::test]
+    async fn session_id_outlives_a_source_port_change() {
+        let dst = dst(|req| req.to_vec()).await;
+        let server = serve(&["--session-id", "--codec", "raw-passthrough"], dst).await;
+        let id = 0x0123_4567_89ab_cdef_u64.to_be_bytes();
+
+        // the same client behind a NAT that picked a new port midway
+        let before = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let after = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for (usock, datagram) in [(&before, &b"one"[..]), (&after, b"two")] {
+            usock
+                .send_to(&[&id[..], datagram].concat(), server.listen)
+                .await
+                .unwrap();
+            assert_eq!(recv(usock).await, (datagram.to_vec(), server.listen));
+        }
+
+        let table = server.table.lock().await;
+        assert_eq!(table.len(), 1);
+        let session = &table[&SessionKey::Id(u64::from_be_bytes(id))];
+        assert_eq!(*session.peer.borrow(), after.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn session_id_leaves_max_datagram_to_the_payload() {
+        let dst = dst(|req| req.to_vec()).await;
+        let args = ["--session-id", "--codec", "raw-passthrough"];
+        let server = serve(&args, dst).await;
+        let client = serve(&[&["-c"][..], &args].concat(), server.listen).await;
+        let app = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let max = usize::from(client.config.max_datagram);
+        assert_eq!(max, usize::from(server.config.max_datagram));
+        for len in [max - SESSION_ID_L, max - SESSION_ID_L + 1, max] {
+            let buf = payload(len);
+            app.send_to(&buf, client.listen).await.unwrap();
+            assert_eq!(recv(&app).await, (buf, client.listen));
+        }
+        for ctx in [&server, &client] {
+            assert_eq!(ctx.stats.oversized.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    /// where the session of `client` relays from, once it has a socket
+    async fn relay_local(ctx: &Context, client: &UdpSocket) -> Option<SocketAddr> {
+        let key = SessionKey::Addr(client.local_addr().unwrap());
+        ctx.table.lock().await.get(&key)?.local
+    }
+
+    #[tokio::test]
+    async fn sessions_keep_their_relay_port() {
+        let dst = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = serve(&["--codec", "raw-passthrough"], dst.local_addr().unwrap()).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut ports = vec![];
+        for i in 0..10 {
+            client.send_to(&[i], server.listen).await.unwrap();
+            let (datagram, from) = recv(&dst).await;
+            assert_eq!(datagram, [i]);
+            dst.send_to(&[i], from).await.unwrap();
+            assert_eq!(recv(&client).await.0, [i]);
+
+            let local = relay_local(&server, &client).await.unwrap();
+            assert_eq!(from.port(), local.port());
+            ports.push(local.port());
+        }
+        assert!(ports.iter().all(|&port| port == ports[0]), "{:?}", ports);
+    }
+
+    #[tokio::test]
+    async fn failing_relay_socket_is_replaced() {
+        // a socket without SO_BROADCAST can't send there
+        let server = serve(
+            &["--codec", "raw-passthrough"],
+            "255.255.255.255:9".parse().unwrap(),
+        )
+        .await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        client.send_to(b"0", server.listen).await.unwrap();
+        let first = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match relay_local(&server, &client).await {
+                    Some(local) => break local,
+                    None => tokio::task::yield_now().await,
                 }
             }
+        })
+        .await
+        .expect("no relay socket");
+
+        for i in 1..REBIND_AFTER {
+            client.send_to(&[i as u8], server.listen).await.unwrap();
         }
-        Err(err) => {
-            warn!("{}", err);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while relay_local(&server, &client).await == Some(first) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("relay socket kept");
+        assert_eq!(
+            server.dsts.primary_health.failures.load(Ordering::Relaxed),
+            REBIND_AFTER.into()
+        );
+    }
+
+    #[test]
+    fn moved_destinations_are_followed() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let dsts = destinations(addr("192.0.2.1:53"), Some(addr("192.0.2.2:53")));
+
+        dsts.moved(false, addr("192.0.2.3:53"));
+        assert_eq!(dsts.current(), addr("192.0.2.3:53"));
+        assert!(dsts.contains(addr("192.0.2.3:53")));
+        assert!(!dsts.contains(addr("192.0.2.1:53")));
+
+        // failing over goes to where the backup is now
+        dsts.moved(true, addr("192.0.2.4:53"));
+        dsts.primary_failed();
+        assert_eq!(dsts.current(), addr("192.0.2.4:53"));
+        assert!(dsts.contains(addr("192.0.2.4:53")));
+        assert!(!dsts.contains(addr("192.0.2.2:53")));
+    }
+
+    #[tokio::test]
+    async fn running_sessions_follow_a_moved_dst() {
+        let old = dst(|req| [&b"old "[..], req].concat()).await;
+        let new = dst(|req| [&b"new "[..], req].concat()).await;
+        let server = serve(&["--codec", "raw-passthrough"], old).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        client.send_to(b"one", server.listen).await.unwrap();
+        assert_eq!(recv(&client).await.0, b"old one");
+        server.dsts.moved(false, new);
+        client.send_to(b"two", server.listen).await.unwrap();
+        assert_eq!(recv(&client).await.0, b"new two");
+        assert_eq!(server.table.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failover_reaches_a_backup_of_the_other_family() {
+        let primary = dst(|req| [&b"v4 "[..], req].concat()).await;
+        let backup = dst_on("[::1]:0", |req| [&b"v6 "[..], req].concat()).await;
+        let server = serve_dsts(
+            &["--codec", "raw-passthrough"],
+            destinations(primary, Some(backup)),
+        )
+        .await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        client.send_to(b"one", server.listen).await.unwrap();
+        assert_eq!(recv(&client).await.0, b"v4 one");
+        server.dsts.primary_failed();
+        client.send_to(b"two", server.listen).await.unwrap();
+        assert_eq!(recv(&client).await.0, b"v6 two");
+        let local = relay_local(&server, &client).await.unwrap();
+        assert!(local.is_ipv6());
+    }
+
+    #[tokio::test]
+    async fn refused_primary_fails_over() {
+        // nothing listens there any more, the kernel answers with port unreachable
+        let primary = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let backup = dst(|req| [&b"backup "[..], req].concat()).await;
+        let server = serve_dsts(
+            &["--codec", "raw-passthrough"],
+            destinations(primary, Some(backup)),
+        )
+        .await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // the refusal of the first is in before the second goes out, and sends that to the backup
+        client.send_to(b"one", server.listen).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.send_to(b"two", server.listen).await.unwrap();
+        assert_eq!(recv(&client).await.0, b"backup two");
+        assert_eq!(server.dsts.current(), backup);
+        assert_eq!(
+            server.dsts.primary_health.failures.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_primary_fails_over() {
+        let primary = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let backup = dst(|req| [&b"backup "[..], req].concat()).await;
+        let server = serve_dsts(
+            &["--codec", "raw-passthrough", "--failover-after", "5"],
+            destinations(primary.local_addr().unwrap(), Some(backup)),
+        )
+        .await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        client.send_to(b"one", server.listen).await.unwrap();
+        assert_eq!(recv(&primary).await.0, b"one");
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        assert_eq!(server.dsts.current(), primary.local_addr().unwrap());
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(server.dsts.current(), backup);
+        client.send_to(b"two", server.listen).await.unwrap();
+        assert_eq!(recv(&client).await.0, b"backup two");
+        assert_eq!(
+            server.dsts.primary_health.failures.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn rates_are_positive() {
+        for flag in ["--session-rate", "--new-session-rate"] {
+            assert!(try_config(&[flag, "0"]).is_err());
+            assert!(try_config(&[flag, "1"]).is_ok());
         }
-    };
-    None
+    }
+
+    #[test]
+    fn inconsistent_options_are_refused() {
+        for (args, problem) in [
+            (&["--session-burst", "10"][..], "--session-burst needs"),
+            (&["--failback-after", "10"], "--failback-after needs"),
+            (&["-c", "--fixed-id", "1"], "--fixed-id only applies"),
+            (
+                &["--codec", "raw-passthrough", "--fixed-id", "1"],
+                "--fixed-id has no effect",
+            ),
+        ] {
+            let problems = check::validate(&config(args));
+            assert!(
+                problems.iter().any(|p| p.starts_with(problem)),
+                "{:?}",
+                args
+            );
+        }
+        let fine = config(&["--codec", "raw-passthrough", "--session-rate", "10"]);
+        assert!(check::validate(&fine).is_empty());
+        assert!(try_config(&["-c", "--proxy-protocol"]).is_err());
+    }
+
+    #[test]
+    fn mapped_clients_share_the_ipv4_key() {
+        let key = |addr: &str| client_key(addr.parse().unwrap());
+        assert_eq!(key("[::ffff:1.2.3.4]:5"), key("1.2.3.4:5"));
+        assert_eq!(key("[::ffff:1.2.3.4]:5").to_string(), "1.2.3.4:5");
+        assert_ne!(key("[::ffff:1.2.3.4]:5"), key("1.2.3.4:6"));
+        for v6 in [
+            "[2001:db8::1]:5",
+            "[::1]:5",
+            "[::1.2.3.4]:5",
+            "[64:ff9b::102:304]:5",
+        ] {
+            assert_eq!(key(v6), v6.parse().unwrap());
+        }
+    }
+
+    /// datagrams sent as soon as the bucket allows, the way a relay does
+    #[tokio::test(start_paused = true)]
+    async fn session_rate_holds_sustained_rate() {
+        let (rate, burst, size) = (10_000.0, 3_000.0, 1_200.0);
+        let mut bucket = TokenBucket::new(rate, burst);
+
+        let start = Instant::now();
+        let (mut ready, mut sent) = (None, 0.0);
+        while start.elapsed() < Duration::from_secs(10) {
+            if let Some(at) = ready {
+                tokio::time::sleep_until(at).await;
+            }
+            sent += size;
+            ready = bucket.reserve(size);
+        }
+
+        let secs = start.elapsed().as_secs_f64();
+        assert!(
+            sent <= burst + rate * secs + size,
+            "{} bytes in {}s",
+            sent,
+            secs
+        );
+        assert!(sent >= rate * secs * 0.95, "{} bytes in {}s", sent, secs);
+    }
 }
@@ -0,0 +1,303 @@
+use log::debug;
+use std::{
+    fs::File,
+    io::{Result, Write},
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{any_addr, client_key, resolve, Config};
+
+/// packets starting with their IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+/// most payload bytes captured, what fits an IPv4 packet
+const SNAPLEN: usize = 65507;
+const UDP: u8 = 17;
+
+/// where `--mirror` copies the datagrams going through the tunnel, decoded
+pub enum Mirror {
+    /// each payload as one datagram
+    Udp(UdpSocket, SocketAddr),
+    /// a pcap file of the payloads in made-up IP and UDP headers between the session's
+    /// client and dst
+    Pcap(Mutex<File>),
+}
+
+impl Mirror {
+    /// a pcap file if `to` has a '/' in it, e.g. "./mirror.pcap", otherwise a UDP address
+    pub async fn open(to: &str, config: &Config) -> Result<Self> {
+        if to.contains('/') {
+            let mut file = File::create(to)?;
+            // magic, version 2.4, UTC, no accuracy given, snaplen and link type
+            file.write_all(
+                &[
+                    &0xa1b2c3d4_u32.to_le_bytes()[..],
+                    &2_u16.to_le_bytes(),
+                    &4_u16.to_le_bytes(),
+                    &0_u32.to_le_bytes(),
+                    &0_u32.to_le_bytes(),
+                    &65535_u32.to_le_bytes(),
+                    &LINKTYPE_RAW.to_le_bytes(),
+                ]
+                .concat(),
+            )?;
+            return Ok(Mirror::Pcap(Mutex::new(file)));
+        }
+
+        let sink = resolve(to, config).await?;
+        let usock = UdpSocket::bind(any_addr(sink)).await?;
+        Ok(Mirror::Udp(usock, sink))
+    }
+
+    /// copies `payload` going from `src` to `dst`; failures are only logged, the relay
+    /// goes on regardless
+    pub async fn copy(&self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        let r = match self {
+            Mirror::Udp(usock, sink) => usock.send_to(payload, sink).await.map(drop),
+            Mirror::Pcap(file) => {
+                let packet = packet(src, dst, payload);
+                let ts = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                // bytes past SNAPLEN are cut off
+                let orig = packet.len() - payload.len().min(SNAPLEN) + payload.len();
+                let record = [
+                    &(ts.as_secs() as u32).to_le_bytes()[..],
+                    &ts.subsec_micros().to_le_bytes(),
+                    &(packet.len() as u32).to_le_bytes(),
+                    &(orig as u32).to_le_bytes(),
+                    &packet,
+                ]
+                .concat();
+                file.lock().unwrap().write_all(&record)
+            }
+        };
+        if let Err(err) = r {
+            debug!(
+                "cannot mirror {} bytes from {}: {}",
+                payload.len(),
+                src,
+                err
+            );
+        }
+    }
+}
+
+/// `payload` in IPv4 or IPv6 and UDP headers, IPv6 with both addresses if either is
+fn packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let (src, dst) = (client_key(src), client_key(dst));
+    let payload = &payload[..payload.len().min(SNAPLEN)];
+    let udp_len = (8 + payload.len()) as u16;
+
+    let v6 = |ip: IpAddr| match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+    let (mut p, pseudo) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let mut ip = vec![0x45, 0];
+            ip.extend_from_slice(&(20 + udp_len).to_be_bytes());
+            ip.extend_from_slice(&[0, 0, 0x40, 0, 64, UDP, 0, 0]);
+            ip.extend_from_slice(&s.octets());
+            ip.extend_from_slice(&d.octets());
+            let sum = checksum(&ip);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+
+            let pseudo = [
+                &s.octets()[..],
+                &d.octets(),
+                &[0, UDP],
+                &udp_len.to_be_bytes(),
+            ]
+            .concat();
+            (ip, pseudo)
+        }
+        (s, d) => {
+            let (s, d) = (v6(s), v6(d));
+            let mut ip = vec![0x60, 0, 0, 0];
+            ip.extend_from_slice(&udp_len.to_be_bytes());
+            ip.extend_from_slice(&[UDP, 64]);
+            ip.extend_from_slice(&s.octets());
+            ip.extend_from_slice(&d.octets());
+
+            let pseudo = [
+                &s.octets()[..],
+                &d.octets(),
+                &u32::from(udp_len).to_be_bytes(),
+                &[0, 0, 0, UDP],
+            ]
+            .concat();
+            (ip, pseudo)
+        }
+    };
+
+    let mut udp = [
+        &src.port().to_be_bytes()[..],
+        &dst.port().to_be_bytes(),
+        &udp_len.to_be_bytes(),
+        &[0, 0],
+        payload,
+    ]
+    .concat();
+    // all zeros means no checksum, sent as all ones instead
+    let sum = match checksum(&[&pseudo[..], &udp].concat()) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+    p.extend_from_slice(&udp);
+    p
+}
+
+/// the internet checksum of `data`
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, payload};
+
+    /// the UDP header and payload of `p`, after checking the IP header around them
+    fn udp_of(p: &[u8], src: IpAddr, dst: IpAddr) -> &[u8] {
+        let (udp, pseudo) = match p[0] >> 4 {
+            4 => {
+                let (ip, udp) = p.split_at(20);
+                assert_eq!(usize::from(u16::from_be_bytes([ip[2], ip[3]])), p.len());
+                assert_eq!(ip[9], UDP);
+                assert_eq!(checksum(ip), 0);
+                assert_eq!(&ip[12..20], &[octets(src), octets(dst)].concat()[..]);
+                let pseudo = [&ip[12..20], &[0, UDP], &(udp.len() as u16).to_be_bytes()].concat();
+                (udp, pseudo)
+            }
+            6 => {
+                let (ip, udp) = p.split_at(40);
+                assert_eq!(usize::from(u16::from_be_bytes([ip[4], ip[5]])), udp.len());
+                assert_eq!(ip[6], UDP);
+                assert_eq!(&ip[8..40], &[octets(src), octets(dst)].concat()[..]);
+                let pseudo = [
+                    &ip[8..40],
+                    &(udp.len() as u32).to_be_bytes(),
+                    &[0, 0, 0, UDP],
+                ]
+                .concat();
+                (udp, pseudo)
+            }
+            v => panic!("IP version {}", v),
+        };
+        assert_eq!(usize::from(u16::from_be_bytes([udp[4], udp[5]])), udp.len());
+        assert_ne!(&udp[6..8], &[0, 0]);
+        assert_eq!(checksum(&[&pseudo[..], udp].concat()), 0);
+        udp
+    }
+
+    fn octets(ip: IpAddr) -> Vec<u8> {
+        match ip {
+            IpAddr::V4(v4) => v4.octets().to_vec(),
+            IpAddr::V6(v6) => v6.octets().to_vec(),
+        }
+    }
+
+    #[test]
+    fn packets_carry_the_payload_with_valid_headers() {
+        let v4: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:198.51.100.7]:53".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        let v4_mapped = |a: SocketAddr| match a.ip() {
+            IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+            ip => ip,
+        };
+
+        // a mapped address is written as the IPv4 one, IPv6 turns both into IPv6
+        for (src, dst, ips, version) in [
+            (v4, mapped, (v4.ip(), client_key(mapped).ip()), 4),
+            (v4, v6, (v4_mapped(v4), v6.ip()), 6),
+            (v6, v6, (v6.ip(), v6.ip()), 6),
+        ] {
+            for len in [0, 1, 2, 1000, 1001] {
+                let buf = payload(len);
+                let p = packet(src, dst, &buf);
+                assert_eq!(p[0] >> 4, version);
+                let udp = udp_of(&p, ips.0, ips.1);
+                assert_eq!(u16::from_be_bytes([udp[0], udp[1]]), src.port());
+                assert_eq!(u16::from_be_bytes([udp[2], udp[3]]), dst.port());
+                assert_eq!(&udp[8..], &buf[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn checksum_of_known_header() {
+        // a textbook IPv4 header, whose checksum is 0xb861
+        let mut ip = [
+            0x45, 0, 0, 0x73, 0, 0, 0x40, 0, 0x40, 0x11, 0, 0, 0xc0, 0xa8, 0, 0x01, 0xc0, 0xa8, 0,
+            0xc7,
+        ];
+        assert_eq!(checksum(&ip), 0xb861);
+        ip[10..12].copy_from_slice(&0xb861_u16.to_be_bytes());
+        assert_eq!(checksum(&ip), 0);
+    }
+
+    #[tokio::test]
+    async fn udp_sink_gets_the_forwarded_bytes() {
+        let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let usock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mirror = Mirror::Udp(usock, sink.local_addr().unwrap());
+
+        let (src, dst) = (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        );
+        let mut received = vec![0; 0x10000];
+        for len in [0, 1, 1000, 60_000] {
+            let buf = payload(len);
+            mirror.copy(src, dst, &buf).await;
+            let n = sink.recv(&mut received).await.unwrap();
+            assert_eq!(&received[..n], &buf[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn pcap_records_the_forwarded_bytes() {
+        let path = std::env::temp_dir().join(format!("udp2dns-mirror-{}.pcap", std::process::id()));
+        let mirror = Mirror::open(path.to_str().unwrap(), &config(&[]))
+            .await
+            .unwrap();
+
+        let src: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let dst: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let bufs = [payload(0), payload(1000)];
+        for buf in &bufs {
+            mirror.copy(src, dst, buf).await;
+        }
+        drop(mirror);
+
+        let pcap = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let u32_at = |i: usize| u32::from_le_bytes(pcap[i..i + 4].try_into().unwrap()) as usize;
+        assert_eq!(u32_at(0), 0xa1b2c3d4);
+        assert_eq!(u32_at(20), LINKTYPE_RAW as usize);
+
+        let mut at = 24;
+        for buf in &bufs {
+            let (captured, orig) = (u32_at(at + 8), u32_at(at + 12));
+            assert_eq!((captured, orig), (28 + buf.len(), 28 + buf.len()));
+            let p = &pcap[at + 16..at + 16 + captured];
+            assert_eq!(&udp_of(p, src.ip(), dst.ip())[8..], &buf[..]);
+            at += 16 + captured;
+        }
+        assert_eq!(at, pcap.len());
+    }
+}
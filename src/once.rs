@@ -0,0 +1,167 @@
+use log::debug;
+use std::{
+    fmt,
+    io::{Error, ErrorKind, Read, Result, Write},
+    net::SocketAddr,
+};
+
+use bytes::Bytes;
+
+use tokio::{
+    net::UdpSocket,
+    time::{self, Duration, Instant},
+};
+
+use crate::{resolve, Config, MAX_DATAGRAM};
+
+/// exit status when nothing came back within `--timeout`
+pub const EXIT_TIMEOUT: i32 = 2;
+
+/// the error of `once` when nothing came back, which `main` exits with `EXIT_TIMEOUT` for
+#[derive(Debug)]
+pub struct NoReply {
+    dst: SocketAddr,
+    timeout: u64,
+}
+
+impl fmt::Display for NoReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no reply from {} within {}s", self.dst, self.timeout)
+    }
+}
+
+impl std::error::Error for NoReply {}
+
+/// sends the datagram read from stdin to dst from `listen` and writes the reply, decoded
+/// with `--client`, to stdout; fails with `NoReply` if there is none
+pub async fn once(config: &Config) -> Result<()> {
+    let mut datagram = Vec::new();
+    std::io::stdin().read_to_end(&mut datagram)?;
+
+    let reply = exchange(config, datagram).await?;
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(&reply)?;
+    stdout.flush()
+}
+
+/// the reply to `datagram`
+async fn exchange(config: &Config, mut datagram: Vec<u8>) -> Result<Bytes> {
+    let (listen, dst) = (
+        config.listen.as_deref().unwrap(),
+        config.dst.as_deref().unwrap(),
+    );
+
+    if datagram.len() > config.max_datagram.into() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} bytes on stdin, over --max-datagram {}",
+                datagram.len(),
+                config.max_datagram
+            ),
+        ));
+    }
+    if config.client && config.session_id {
+        datagram.splice(0..0, rand::random::<u64>().to_be_bytes());
+    }
+
+    let dst = resolve(dst, config).await?;
+    let usock = UdpSocket::bind(listen).await?;
+    usock.send_to(&datagram, dst).await?;
+    debug!("sent {} bytes to {}", datagram.len(), dst);
+
+    let timeout = Duration::from_secs(config.timeout);
+    let deadline = Instant::now() + timeout;
+    #[cfg(feature = "dns")]
+    let (stats, mut reassembly) = (
+        crate::Stats::default(),
+        crate::dns::Reassembly::new(timeout, config.fec_group, config.max_pending_bytes),
+    );
+
+    let mut buf = vec![0_u8; MAX_DATAGRAM];
+    loop {
+        let (received, from) = match time::timeout_at(deadline, usock.recv_from(&mut buf)).await {
+            Ok(r) => r?,
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    NoReply {
+                        dst,
+                        timeout: config.timeout,
+                    },
+                ))
+            }
+        };
+        if from != dst {
+            debug!("ignored {} bytes from {}", received, from);
+            continue;
+        }
+
+        // fragments of a split reply come in one by one
+        #[cfg(feature = "dns")]
+        if config.client && config.codec == crate::Codec::Txt {
+            match crate::dns::dns_reply_decode(&buf[..received], &mut reassembly, config, &stats) {
+                Some(reply) => return Ok(reply),
+                None => continue,
+            }
+        }
+        return Ok(Bytes::copy_from_slice(&buf[..received]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, dst};
+
+    /// `args` sending to `dst` from any port
+    fn once_to(dst: SocketAddr, args: &[&str]) -> Config {
+        let mut config = config(&[&["--once"][..], args].concat());
+        config.dst = Some(dst.to_string());
+        config
+    }
+
+    #[tokio::test]
+    async fn reply_is_returned() {
+        let dst = dst(|req| [&b"re: "[..], req].concat()).await;
+        let config = once_to(dst, &["--codec", "raw-passthrough"]);
+        let reply = exchange(&config, b"ping".to_vec()).await.unwrap();
+        assert_eq!(&reply[..], b"re: ping");
+    }
+
+    #[cfg(feature = "dns")]
+    #[tokio::test]
+    async fn split_reply_is_decoded() {
+        let server = config(&["--max-size", "512"]);
+        let buf = crate::testing::payload(3000);
+        let msgs = crate::dns::dns_reply_encode(&buf, &server);
+        assert!(msgs.len() > 1);
+
+        // a server sending every fragment of the reply
+        let usock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dst = usock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut req = [0_u8; 16];
+            let (_, from) = usock.recv_from(&mut req).await.unwrap();
+            for msg in msgs {
+                usock.send_to(&msg, from).await.unwrap();
+            }
+        });
+
+        let config = once_to(dst, &["-c"]);
+        let reply = exchange(&config, b"ping".to_vec()).await.unwrap();
+        assert_eq!(&reply[..], &buf[..]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silence_is_no_reply() {
+        let usock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = once_to(
+            usock.local_addr().unwrap(),
+            &["--codec", "raw-passthrough", "--timeout", "3"],
+        );
+        let err = exchange(&config, b"ping".to_vec()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(err.get_ref().unwrap().is::<NoReply>());
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+};
+
+use tokio::{
+    net::UdpSocket,
+    time::{self, Duration, Instant},
+};
+
+use trust_dns_proto::{
+    op::{Edns, Message, Query},
+    rr::{Name, RecordType},
+};
+
+use crate::BUF_SIZE;
+
+/// EDNS payload sizes tried, `None` being a plain query
+const EDNS_SIZES: [Option<u16>; 4] = [None, Some(1232), Some(4096), Some(65535)];
+
+/// sends TXT queries for `name` to `resolver` and prints what the path does to them
+pub async fn probe(
+    resolver: SocketAddr,
+    name: &str,
+    count: usize,
+    timeout: Duration,
+) -> Result<()> {
+    let name = Name::from_ascii(name).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+
+    let usock = UdpSocket::bind(match resolver {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    })
+    .await?;
+    usock.connect(resolver).await?;
+
+    println!("probing {} with TXT queries for {}", resolver, name);
+
+    for size in EDNS_SIZES {
+        let label = size.map_or(String::from("no edns"), |s| format!("edns {}", s));
+
+        match query(&usock, &name, size, timeout).await? {
+            Some((size, msg, rtt)) => println!(
+                "{:>10}: {} bytes, {} answers{}{}, {}ms",
+                label,
+                size,
+                msg.answers().len(),
+                if msg.truncated() { ", truncated" } else { "" },
+                msg.extensions().as_ref().map_or(String::new(), |e| format!(
+                    ", resolver accepts {}",
+                    e.max_payload()
+                )),
+                rtt.as_millis()
+            ),
+            None => println!("{:>10}: no answer", label),
+        }
+    }
+
+    let mut rtts = vec![];
+    let mut answers: Vec<Vec<String>> = vec![];
+    for _ in 0..count {
+        if let Some((_, msg, rtt)) = query(&usock, &name, Some(4096), timeout).await? {
+            rtts.push(rtt);
+            answers.push(msg.answers().iter().map(|r| r.to_string()).collect());
+        }
+    }
+
+    if rtts.is_empty() {
+        println!("round trip: no answers to {} queries", count);
+        return Ok(());
+    }
+
+    println!(
+        "round trip: {}/{} answered, min {}ms, avg {}ms, max {}ms",
+        rtts.len(),
+        count,
+        rtts.iter().min().unwrap().as_millis(),
+        (rtts.iter().sum::<Duration>() / rtts.len() as u32).as_millis(),
+        rtts.iter().max().unwrap().as_millis()
+    );
+
+    let first = &answers[0];
+    let mut sorted = first.clone();
+    sorted.sort();
+
+    println!(
+        "answers: {}",
+        if answers.iter().all(|a| a == first) {
+            "identical on every query"
+        } else if answers.iter().all(|a| {
+            let mut a = a.clone();
+            a.sort();
+            a == sorted
+        }) {
+            "reordered between queries, the path doesn't keep record order"
+        } else {
+            "differ between queries, the path doesn't keep TXT data intact"
+        }
+    );
+
+    Ok(())
+}
+
+/// one query, returning the answer with its size and round trip
+async fn query(
+    usock: &UdpSocket,
+    name: &Name,
+    edns: Option<u16>,
+    timeout: Duration,
+) -> Result<Option<(usize, Message, Duration)>> {
+    let id = rand::random();
+
+    let mut msg = Message::new();
+    msg.set_id(id)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name.clone(), RecordType::TXT));
+    if let Some(size) = edns {
+        let mut e = Edns::new();
+        e.set_max_payload(size);
+        msg.set_edns(e);
+    }
+
+    let start = Instant::now();
+    usock.send(&msg.to_vec().map_err(Error::other)?).await?;
+
+    let mut buf = vec![0_u8; BUF_SIZE.max(usize::from(edns.unwrap_or(512)))];
+    loop {
+        let received = match time::timeout_at(start + timeout, usock.recv(&mut buf)).await {
+            Ok(r) => r?,
+            Err(_) => return Ok(None),
+        };
+
+        // late answers to earlier queries are skipped
+        match Message::from_vec(&buf[..received]) {
+            Ok(msg) if msg.id() == id => return Ok(Some((received, msg, start.elapsed()))),
+            _ => continue,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{any_addr, client_key};
+
+/// leads every PROXY protocol v2 header
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// version 2, PROXY command
+const VERSION_PROXY: u8 = 0x21;
+/// address family in the high nibble, UDP in the low one
+const INET_DGRAM: u8 = 0x12;
+const INET6_DGRAM: u8 = 0x22;
+
+/// PROXY protocol v2 header of a datagram from `src` to `dst`, with both addresses given
+/// as IPv6 if either is
+pub fn header(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src, dst) = (client_key(src), client_key(dst));
+
+    let mut h = SIGNATURE.to_vec();
+    h.push(VERSION_PROXY);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            h.push(INET_DGRAM);
+            h.extend_from_slice(&12_u16.to_be_bytes());
+            h.extend_from_slice(&s.octets());
+            h.extend_from_slice(&d.octets());
+        }
+        (s, d) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            h.push(INET6_DGRAM);
+            h.extend_from_slice(&36_u16.to_be_bytes());
+            h.extend_from_slice(&v6(s).octets());
+            h.extend_from_slice(&v6(d).octets());
+        }
+    }
+    h.extend_from_slice(&src.port().to_be_bytes());
+    h.extend_from_slice(&dst.port().to_be_bytes());
+    h
+}
+
+/// the address `peer` sent to, to reach the listener on `listen`; for a listener on the
+/// wildcard address, the local address the kernel routes to `peer` from
+pub fn reached(peer: SocketAddr, listen: SocketAddr) -> SocketAddr {
+    if !listen.ip().is_unspecified() {
+        return listen;
+    }
+
+    let route = || {
+        let usock = std::net::UdpSocket::bind(any_addr(peer))?;
+        usock.connect(peer)?;
+        usock.local_addr()
+    };
+    match route() {
+        Ok(local) => SocketAddr::new(local.ip(), listen.port()),
+        Err(_) => listen,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn headers() {
+        let v4 = header(addr("192.0.2.1:1000"), addr("198.51.100.2:53"));
+        assert_eq!(
+            v4[12..],
+            [0x21, 0x12, 0, 12, 192, 0, 2, 1, 198, 51, 100, 2, 0x03, 0xe8, 0, 53]
+        );
+
+        // v4-mapped addresses are given as the IPv4 ones
+        let mapped = header(addr("[::ffff:192.0.2.1]:1000"), addr("198.51.100.2:53"));
+        assert_eq!(mapped, v4);
+
+        let v6 = header(addr("[2001:db8::1]:1000"), addr("198.51.100.2:53"));
+        assert_eq!(v6[12..16], [0x21, 0x22, 0, 36]);
+        assert_eq!(v6.len(), 16 + 36);
+        let dst: std::net::Ipv6Addr = "::ffff:198.51.100.2".parse().unwrap();
+        assert_eq!(v6[32..48], dst.octets());
+    }
+
+    #[test]
+    fn wildcard_listener_is_reached_on_a_local_address() {
+        let peer = addr("127.0.0.1:5000");
+        assert_eq!(reached(peer, addr("0.0.0.0:53")), addr("127.0.0.1:53"));
+        assert_eq!(reached(peer, addr("127.0.0.2:53")), addr("127.0.0.2:53"));
+    }
+}
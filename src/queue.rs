@@ -0,0 +1,192 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use tokio::sync::{mpsc::error::TrySendError, Notify};
+
+/// what a full session queue gives up
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Overflow {
+    /// the datagram that didn't fit, keeping what's queued in order
+    DropNewest,
+    /// the longest queued datagram, for protocols where only fresh data counts
+    DropOldest,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    notify: Notify,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+struct State {
+    queue: VecDeque<Bytes>,
+    closed: bool,
+}
+
+/// queue of datagrams from the listener to a relay, like a tokio mpsc channel with a single
+/// sender but dropping by `overflow` when full
+pub fn channel(capacity: usize, overflow: Overflow) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            closed: false,
+        }),
+        notify: Notify::new(),
+        capacity,
+        overflow,
+    });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+pub struct Sender(Arc<Shared>);
+
+impl Sender {
+    /// queues `b`; `Full` carries whichever datagram got dropped for it, `b` itself or the
+    /// oldest one
+    pub fn try_send(&self, b: Bytes) -> Result<(), TrySendError<Bytes>> {
+        let mut state = self.0.state.lock().unwrap();
+        if state.closed {
+            return Err(TrySendError::Closed(b));
+        }
+
+        let dropped = if state.queue.len() < self.0.capacity {
+            None
+        } else {
+            match self.0.overflow {
+                Overflow::DropNewest => return Err(TrySendError::Full(b)),
+                Overflow::DropOldest => state.queue.pop_front(),
+            }
+        };
+        state.queue.push_back(b);
+        drop(state);
+
+        self.0.notify.notify_one();
+        match dropped {
+            Some(oldest) => Err(TrySendError::Full(oldest)),
+            None => Ok(()),
+        }
+    }
+
+    /// whether the receiver was closed or dropped
+    pub fn is_closed(&self) -> bool {
+        self.0.state.lock().unwrap().closed
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().closed = true;
+        self.0.notify.notify_one();
+    }
+}
+
+pub struct Receiver(Arc<Shared>);
+
+impl Receiver {
+    /// next datagram, or `None` once the queue is closed and drained
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        loop {
+            {
+                let mut state = self.0.state.lock().unwrap();
+                if let Some(b) = state.queue.pop_front() {
+                    return Some(b);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // a notification sent meanwhile is kept as a permit, nothing is missed
+            self.0.notify.notified().await;
+        }
+    }
+
+    /// takes no more datagrams, those already queued can still be received
+    pub fn close(&mut self) {
+        self.0.state.lock().unwrap().closed = true;
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(i: u8) -> Bytes {
+        Bytes::copy_from_slice(&[i])
+    }
+
+    /// what queueing datagrams 0 to 4 into a queue of 3 returns
+    fn saturated(tx: &Sender) -> Vec<Result<(), TrySendError<Bytes>>> {
+        (0..5).map(|i| tx.try_send(datagram(i))).collect()
+    }
+
+    async fn drain(mut rx: Receiver) -> Vec<Bytes> {
+        rx.close();
+        let mut received = vec![];
+        while let Some(b) = rx.recv().await {
+            received.push(b);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_what_is_queued() {
+        let (tx, rx) = channel(3, Overflow::DropNewest);
+        let sent = saturated(&tx);
+        assert!(sent[..3].iter().all(Result::is_ok));
+        assert!(matches!(&sent[3], Err(TrySendError::Full(b)) if *b == datagram(3)));
+        assert!(matches!(&sent[4], Err(TrySendError::Full(b)) if *b == datagram(4)));
+        assert_eq!(drain(rx).await, [datagram(0), datagram(1), datagram(2)]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_freshest() {
+        let (tx, rx) = channel(3, Overflow::DropOldest);
+        let sent = saturated(&tx);
+        assert!(sent[..3].iter().all(Result::is_ok));
+        assert!(matches!(&sent[3], Err(TrySendError::Full(b)) if *b == datagram(0)));
+        assert!(matches!(&sent[4], Err(TrySendError::Full(b)) if *b == datagram(1)));
+        assert_eq!(drain(rx).await, [datagram(2), datagram(3), datagram(4)]);
+    }
+
+    #[tokio::test]
+    async fn closed_receiver_is_drained_then_ends() {
+        let (tx, mut rx) = channel(3, Overflow::DropNewest);
+        tx.try_send(datagram(0)).unwrap();
+        rx.close();
+        assert!(tx.is_closed());
+        assert!(matches!(
+            tx.try_send(datagram(1)),
+            Err(TrySendError::Closed(_))
+        ));
+        assert_eq!(rx.recv().await, Some(datagram(0)));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn dropped_receiver_closes_the_sender() {
+        let (tx, rx) = channel(3, Overflow::DropOldest);
+        assert!(!tx.is_closed());
+        drop(rx);
+        assert!(tx.is_closed());
+    }
+
+    #[tokio::test]
+    async fn dropped_sender_ends_a_waiting_receiver() {
+        let (tx, mut rx) = channel(3, Overflow::DropNewest);
+        let waiting = tokio::spawn(async move { rx.recv().await });
+        tokio::task::yield_now().await;
+        drop(tx);
+        assert_eq!(waiting.await.unwrap(), None);
+    }
+}